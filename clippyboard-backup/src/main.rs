@@ -0,0 +1,108 @@
+//! Exports the whole history to a file and imports it again, for backups and moving to another machine.
+
+use std::fs::OpenOptions;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use clippyboard_shared::HistoryItem;
+use eyre::{Context, bail};
+
+const USAGE: &str = "\
+Usage: clippyboard-backup dump <PATH>
+       clippyboard-backup restore <PATH>
+
+Writes the whole history to a file, or stores the entries of such a file in the history.
+The file contains the entries as CBOR, like the daemon sends them.
+
+Options:
+  -V, --version  Print the version
+  -h, --help     Print this help
+";
+
+fn main() -> std::process::ExitCode {
+    clippyboard_shared::exit::report(run())
+}
+
+fn run() -> eyre::Result<()> {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["dump", path] => dump(Path::new(path)),
+        ["restore", path] => restore(Path::new(path)),
+        ["--help" | "-h"] => {
+            print!("{USAGE}");
+            Ok(())
+        }
+        ["--version" | "-V"] => {
+            println!("{}", clippyboard_shared::version!());
+            Ok(())
+        }
+        _ => bail!("expected dump or restore and a path, see --help"),
+    }
+}
+
+fn dump(path: &Path) -> eyre::Result<()> {
+    let socket_path = clippyboard_shared::socket_path().path;
+    let mut socket = clippyboard_shared::connect(&socket_path)?;
+    socket
+        .write_all(&[clippyboard_shared::MESSAGE_READ])
+        .wrap_err("writing request type")?;
+    let items: Vec<HistoryItem> =
+        ciborium::from_reader(BufReader::new(socket)).wrap_err("reading items from socket")?;
+
+    // The history may contain passwords, so only we may read it.
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .wrap_err_with(|| format!("creating {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    ciborium::into_writer(&items, &mut writer)
+        .wrap_err_with(|| format!("writing {}", path.display()))?;
+    writer
+        .flush()
+        .wrap_err_with(|| format!("writing {}", path.display()))?;
+
+    eprintln!("INFO: wrote {} entries to {}", items.len(), path.display());
+    Ok(())
+}
+
+/// Stores the entries oldest first, so that they keep their order.
+/// They get the current time as their capture time, like everything the daemon stores.
+fn restore(path: &Path) -> eyre::Result<()> {
+    let file = std::fs::File::open(path).wrap_err_with(|| format!("opening {}", path.display()))?;
+    let items: Vec<HistoryItem> = ciborium::from_reader(BufReader::new(file))
+        .wrap_err_with(|| format!("reading entries from {}", path.display()))?;
+
+    let socket_path = clippyboard_shared::socket_path().path;
+    let mut socket = clippyboard_shared::connect(&socket_path)?;
+    let mut writer = BufWriter::new(&mut socket);
+    for item in &items {
+        writer
+            .write_all(&[clippyboard_shared::MESSAGE_STORE_RAW])
+            .wrap_err("writing request type")?;
+        clippyboard_shared::write_framed(&mut writer, item.mime.as_bytes())
+            .wrap_err("writing mime type")?;
+        clippyboard_shared::write_framed(&mut writer, &item.data).wrap_err("writing data")?;
+    }
+    // Messages are handled in order, so the answer to the ping means that everything is stored.
+    writer
+        .write_all(&[clippyboard_shared::MESSAGE_PING])
+        .wrap_err("writing ping message to socket")?;
+    writer.flush().wrap_err("writing entries to socket")?;
+    drop(writer);
+
+    let mut response = [0; 1];
+    socket
+        .read_exact(&mut response)
+        .wrap_err("waiting for the daemon to store the entries")?;
+
+    eprintln!(
+        "INFO: restored {} entries from {}",
+        items.len(),
+        path.display()
+    );
+    Ok(())
+}
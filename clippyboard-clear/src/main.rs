@@ -1,18 +1,75 @@
-use std::{io::Write, os::unix::net::UnixStream};
+use std::io::{Read, Write};
 
-use eyre::Context;
+use eyre::{Context, bail};
 
-fn main() -> eyre::Result<()> {
-    let socket_path = clippyboard_shared::socket_path()?;
+const USAGE: &str = "\
+Usage: clippyboard-clear [OPTIONS]
+
+Clears the clipboard history and the clipboard.
+
+Options:
+      --text       Only clear text entries
+      --images     Only clear image entries
+      --force      Also clear tagged entries that keep_tagged keeps
+      --autostart  Start the daemon if it isn't running
+      --undo       Bring back what the last clear removed
+  -V, --version    Print the version
+  -h, --help       Print this help
+";
+
+fn main() -> std::process::ExitCode {
+    clippyboard_shared::exit::report(run())
+}
+
+fn run() -> eyre::Result<()> {
+    let mut category = clippyboard_shared::CLEAR_ALL;
+    let mut autostart = false;
+    let mut undo = false;
+    let mut force = false;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--text" => category = clippyboard_shared::CLEAR_TEXT,
+            "--images" => category = clippyboard_shared::CLEAR_IMAGES,
+            "--autostart" => autostart = true,
+            "--undo" => undo = true,
+            "--force" => force = true,
+            "--help" | "-h" => {
+                print!("{USAGE}");
+                return Ok(());
+            }
+            "--version" | "-V" => {
+                println!("{}", clippyboard_shared::version!());
+                return Ok(());
+            }
+            _ => bail!("unknown argument {arg}, see --help"),
+        }
+    }
+
+    let socket_path = clippyboard_shared::socket_path().path;
+
+    let mut socket = if autostart {
+        clippyboard_shared::connect_or_start_daemon(&socket_path)?
+    } else {
+        clippyboard_shared::connect(&socket_path)?
+    };
+    if undo {
+        socket
+            .write_all(&[clippyboard_shared::MESSAGE_UNDO_CLEAR])
+            .wrap_err("writing undo message to socket")?;
+        let mut undone = [0; 1];
+        socket
+            .read_exact(&mut undone)
+            .wrap_err("reading undo response")?;
+        if undone[0] == 0 {
+            bail!(
+                "there is no clear to undo. it was too long ago or something new was copied since"
+            );
+        }
+        return Ok(());
+    }
 
-    let mut socket = UnixStream::connect(&socket_path).wrap_err_with(|| {
-        format!(
-            "connecting to socket at {}. is the daemon running?",
-            socket_path.display()
-        )
-    })?;
     socket
-        .write_all(&[clippyboard_shared::MESSAGE_CLEAR])
+        .write_all(&[clippyboard_shared::MESSAGE_CLEAR, category, force as u8])
         .wrap_err("writing clear message to socket")?;
 
     Ok(())
@@ -0,0 +1,268 @@
+//! The two protocols for clipboard managers: ext-data-control-v1, and wlr-data-control-unstable-v1,
+//! which it was standardized from and which older wlroots compositors only support.
+//! Apart from the names, they are the same, so the rest of the daemon only uses the traits here
+//! and the implementations for both are generated by one macro.
+
+use crate::{InProgressOffer, OfferData, WlState};
+use std::os::fd::BorrowedFd;
+use wayland_client::QueueHandle;
+use wayland_client::protocol::wl_seat::WlSeat;
+
+pub trait DataControlManager: Send + Sync {
+    /// Name of the Wayland interface, for messages.
+    fn interface_name(&self) -> &'static str;
+    /// `generation` is stored with the device, see [`DataControlDevice::generation`].
+    fn get_data_device(
+        &self,
+        seat: &WlSeat,
+        generation: u64,
+        qh: &QueueHandle<WlState>,
+    ) -> Box<dyn DataControlDevice>;
+    fn destroy(&self);
+}
+
+pub trait DataControlDevice: Send + Sync {
+    /// Offers the data as all of the mime types in the selection or the primary selection,
+    /// or clears it for `None`.
+    fn set_selection(
+        &self,
+        source: Option<(&[String], OfferData)>,
+        primary: bool,
+        qh: &QueueHandle<WlState>,
+    ) -> eyre::Result<()>;
+    fn seat(&self) -> &WlSeat;
+    /// Higher for devices requested later, to tell which device of a seat is the newest.
+    fn generation(&self) -> u64;
+    fn destroy(&self);
+}
+
+pub trait DataControlOffer: Send {
+    fn receive(&self, mime: String, fd: BorrowedFd<'_>);
+    fn in_progress(&self) -> &InProgressOffer;
+    fn destroy(&self);
+}
+
+/// User data of the devices, the source of the selection is created with the device's manager.
+pub struct DeviceData<M> {
+    seat: WlSeat,
+    generation: u64,
+    manager: M,
+}
+
+/// Implements the traits and the event handling for the protocol whose interfaces are imported as
+/// `Manager`, `Device`, `Offer` and `Source`, next to their modules `device`, `offer` and `source`.
+macro_rules! data_control_protocol {
+    () => {
+        use super::{DataControlDevice, DataControlManager, DataControlOffer, DeviceData};
+        use crate::{InProgressOffer, OfferData, WlState};
+        use std::os::fd::BorrowedFd;
+        use std::time::SystemTime;
+        use wayland_client::protocol::wl_seat::WlSeat;
+        use wayland_client::{Dispatch, Proxy, QueueHandle, event_created_child};
+
+        impl DataControlManager for Manager {
+            fn interface_name(&self) -> &'static str {
+                <Manager as Proxy>::interface().name
+            }
+
+            fn get_data_device(
+                &self,
+                seat: &WlSeat,
+                generation: u64,
+                qh: &QueueHandle<WlState>,
+            ) -> Box<dyn DataControlDevice> {
+                let data = DeviceData {
+                    seat: seat.clone(),
+                    generation,
+                    manager: self.clone(),
+                };
+                Box::new(self.get_data_device(seat, qh, data))
+            }
+
+            fn destroy(&self) {
+                self.destroy();
+            }
+        }
+
+        impl DataControlDevice for Device {
+            fn set_selection(
+                &self,
+                source: Option<(&[String], OfferData)>,
+                primary: bool,
+                qh: &QueueHandle<WlState>,
+            ) -> eyre::Result<()> {
+                // The primary selection was only added in version 2 of the wlr protocol.
+                if primary && self.version() < 2 {
+                    eyre::bail!(
+                        "the compositor does not support the primary selection in {}",
+                        <Manager as Proxy>::interface().name
+                    );
+                }
+
+                let source = source.map(|(mime_types, data)| {
+                    let source = device_data(self).manager.create_data_source(qh, data);
+                    for mime in mime_types {
+                        source.offer(mime.clone());
+                    }
+                    source
+                });
+                if primary {
+                    self.set_primary_selection(source.as_ref());
+                } else {
+                    self.set_selection(source.as_ref());
+                }
+                Ok(())
+            }
+
+            fn seat(&self) -> &WlSeat {
+                &device_data(self).seat
+            }
+
+            fn generation(&self) -> u64 {
+                device_data(self).generation
+            }
+
+            fn destroy(&self) {
+                self.destroy();
+            }
+        }
+
+        fn device_data(device: &Device) -> &DeviceData<Manager> {
+            device
+                .data::<DeviceData<Manager>>()
+                .expect("missing DeviceData for data control device")
+        }
+
+        impl DataControlOffer for Offer {
+            fn receive(&self, mime: String, fd: BorrowedFd<'_>) {
+                self.receive(mime, fd);
+            }
+
+            fn in_progress(&self) -> &InProgressOffer {
+                self.data::<InProgressOffer>()
+                    .expect("missing InProgressOffer data for data control offer")
+            }
+
+            fn destroy(&self) {
+                self.destroy();
+            }
+        }
+
+        impl Dispatch<Manager, ()> for WlState {
+            fn event(
+                _state: &mut Self,
+                _proxy: &Manager,
+                _event: <Manager as Proxy>::Event,
+                _data: &(),
+                _conn: &wayland_client::Connection,
+                _qhandle: &QueueHandle<Self>,
+            ) {
+                // no events at the time of writing
+            }
+        }
+
+        impl Dispatch<Device, DeviceData<Manager>> for WlState {
+            fn event(
+                state: &mut Self,
+                proxy: &Device,
+                event: <Device as Proxy>::Event,
+                data: &DeviceData<Manager>,
+                conn: &wayland_client::Connection,
+                qhandle: &QueueHandle<Self>,
+            ) {
+                match event {
+                    device::Event::DataOffer { id: _ } => {
+                        // A new offer is being prepared, we created the associated data in its creation and don't need to do anything
+                    }
+
+                    // The selection has been confirmed, we just properly got a new offer that we should use.
+                    device::Event::Selection { id: Some(offer) } => {
+                        state.on_selection(Box::new(offer), &data.seat);
+                    }
+                    // The offer has been confirmed to be a primary selection, do the necessary bookkeeping but we don't really care.
+                    device::Event::PrimarySelection { id: Some(id) } => {
+                        id.destroy();
+                    }
+                    device::Event::Finished => {
+                        state.on_device_finished(&data.seat, conn, qhandle);
+                        proxy.destroy();
+                    }
+                    _ => {}
+                }
+            }
+
+            event_created_child!(WlState, Device, [
+                device::EVT_DATA_OFFER_OPCODE => (Offer, InProgressOffer {
+                    mime_types: Default::default(),
+                    time: SystemTime::now()
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap(),
+                }),
+            ]);
+        }
+
+        impl Dispatch<Offer, InProgressOffer> for WlState {
+            fn event(
+                _state: &mut Self,
+                _proxy: &Offer,
+                event: <Offer as Proxy>::Event,
+                data: &InProgressOffer,
+                _conn: &wayland_client::Connection,
+                _qhandle: &QueueHandle<Self>,
+            ) {
+                if let offer::Event::Offer { mime_type } = event {
+                    data.mime_types.lock().unwrap().insert(mime_type);
+                }
+            }
+        }
+
+        impl Dispatch<Source, OfferData> for WlState {
+            fn event(
+                state: &mut Self,
+                proxy: &Source,
+                event: <Source as Proxy>::Event,
+                data: &OfferData,
+                _conn: &wayland_client::Connection,
+                _qhandle: &QueueHandle<Self>,
+            ) {
+                match event {
+                    source::Event::Send { mime_type, fd } => {
+                        state.send_selection(data, mime_type, fd);
+                    }
+                    source::Event::Cancelled => {
+                        proxy.destroy();
+                    }
+                    _ => {}
+                }
+            }
+        }
+    };
+}
+
+pub mod ext {
+    use wayland_protocols::ext::data_control::v1::client::{
+        ext_data_control_device_v1 as device,
+        ext_data_control_device_v1::ExtDataControlDeviceV1 as Device,
+        ext_data_control_manager_v1::ExtDataControlManagerV1 as Manager,
+        ext_data_control_offer_v1 as offer,
+        ext_data_control_offer_v1::ExtDataControlOfferV1 as Offer,
+        ext_data_control_source_v1 as source,
+        ext_data_control_source_v1::ExtDataControlSourceV1 as Source,
+    };
+
+    data_control_protocol!();
+}
+
+pub mod wlr {
+    use wayland_protocols_wlr::data_control::v1::client::{
+        zwlr_data_control_device_v1 as device,
+        zwlr_data_control_device_v1::ZwlrDataControlDeviceV1 as Device,
+        zwlr_data_control_manager_v1::ZwlrDataControlManagerV1 as Manager,
+        zwlr_data_control_offer_v1 as offer,
+        zwlr_data_control_offer_v1::ZwlrDataControlOfferV1 as Offer,
+        zwlr_data_control_source_v1 as source,
+        zwlr_data_control_source_v1::ZwlrDataControlSourceV1 as Source,
+    };
+
+    data_control_protocol!();
+}
@@ -0,0 +1,105 @@
+//! A D-Bus interface on the session bus, offering the same operations as the socket.
+//! Only built with the `dbus` feature.
+
+use crate::history::{self, History};
+use crate::peer::{self, Clipboard};
+use eyre::Context;
+use std::sync::Arc;
+use tracing::warn;
+use zbus::object_server::SignalEmitter;
+
+pub const BUS_NAME: &str = "io.github.noratrieb.Clippyboard";
+pub const OBJECT_PATH: &str = "/io/github/noratrieb/Clippyboard";
+
+/// The state shared with the socket, the history and the clipboard it belongs to.
+pub trait State: Clipboard + Send + Sync + 'static {
+    fn history(&self) -> &History;
+}
+
+struct Interface<S> {
+    state: Arc<S>,
+}
+
+#[zbus::interface(name = "io.github.noratrieb.Clippyboard")]
+impl<S: State> Interface<S> {
+    /// Returns `(id, mime, size, created_time, copy_count)` for every item, oldest first.
+    fn list_history(&self) -> Vec<(u64, String, u64, u64, u64)> {
+        let items = self.state.history().items.lock().unwrap();
+        items
+            .iter()
+            .map(|item| {
+                (
+                    item.id,
+                    item.mime.clone(),
+                    item.data.len() as u64,
+                    item.created_time,
+                    item.copy_count,
+                )
+            })
+            .collect()
+    }
+
+    fn copy(&self, id: u64) -> zbus::fdo::Result<()> {
+        peer::copy_item(self.state.history(), &*self.state, id)
+            .map_err(|err| zbus::fdo::Error::Failed(format!("{err:#}")))
+    }
+
+    fn clear(&self) -> zbus::fdo::Result<()> {
+        peer::clear(
+            self.state.history(),
+            &*self.state,
+            clippyboard_shared::CLEAR_ALL,
+            false,
+        )
+        .map_err(|err| zbus::fdo::Error::Failed(format!("{err:#}")))
+    }
+
+    fn delete(&self, id: u64) -> zbus::fdo::Result<()> {
+        if history::remove_item(self.state.history(), id) {
+            Ok(())
+        } else {
+            Err(zbus::fdo::Error::InvalidArgs(format!(
+                "no item with ID {id}"
+            )))
+        }
+    }
+
+    /// Emitted with the ID of every newly stored item.
+    #[zbus(signal)]
+    async fn selection_changed(emitter: &SignalEmitter<'_>, id: u64) -> zbus::Result<()>;
+}
+
+/// Claims the bus name and serves the interface until the process exits.
+pub fn serve<S: State>(state: Arc<S>) -> eyre::Result<()> {
+    let new_items = state.history().subscribe();
+
+    let connection = zbus::blocking::connection::Builder::session()
+        .wrap_err("connecting to the session bus")?
+        .name(BUS_NAME)
+        .wrap_err("requesting bus name")?
+        .serve_at(OBJECT_PATH, Interface { state })
+        .wrap_err("serving interface")?
+        .build()
+        .wrap_err("setting up connection")?;
+
+    let interface = connection
+        .object_server()
+        .interface::<_, Interface<S>>(OBJECT_PATH)
+        .wrap_err("getting interface")?;
+
+    std::thread::spawn(move || {
+        // keeps the connection alive
+        let _connection = connection;
+        for id in new_items {
+            let result = zbus::block_on(Interface::<S>::selection_changed(
+                interface.signal_emitter(),
+                id,
+            ));
+            if let Err(err) = result {
+                warn!("Failed to emit SelectionChanged signal: {err}");
+            }
+        }
+    });
+
+    Ok(())
+}
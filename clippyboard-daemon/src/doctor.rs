@@ -0,0 +1,117 @@
+//! `clippyboard-daemon doctor`, which reports what the compositor supports and whether the daemon
+//! is reachable, to debug a daemon that doesn't start or doesn't see copies.
+
+use crate::MAX_SEAT_VERSION;
+use clippyboard_shared::exit::Failure;
+use eyre::Context;
+use std::sync::Mutex;
+use wayland_client::globals::{GlobalListContents, registry_queue_init};
+use wayland_client::protocol::wl_registry::WlRegistry;
+use wayland_client::protocol::wl_seat::WlSeat;
+use wayland_client::{Connection, Dispatch, Proxy, QueueHandle};
+use wayland_protocols::ext::data_control::v1::client::ext_data_control_manager_v1::ExtDataControlManagerV1;
+use wayland_protocols_wlr::data_control::v1::client::zwlr_data_control_manager_v1::ZwlrDataControlManagerV1;
+
+struct Doctor;
+
+/// Prints the report, failing if the daemon couldn't work with the compositor.
+pub fn run() -> eyre::Result<()> {
+    let conn = Connection::connect_to_env().wrap_err(Failure::WaylandUnavailable)?;
+    let (global_list, mut queue) =
+        registry_queue_init::<Doctor>(&conn).wrap_err("getting the Wayland globals")?;
+    let globals = global_list.contents().clone_list();
+
+    let find = |interface: &str| globals.iter().find(|global| global.interface == interface);
+    let ext = find(ExtDataControlManagerV1::interface().name);
+    let wlr = find(ZwlrDataControlManagerV1::interface().name);
+
+    println!("Clipboard protocols:");
+    for (global, interface) in [
+        (ext, ExtDataControlManagerV1::interface().name),
+        (wlr, ZwlrDataControlManagerV1::interface().name),
+    ] {
+        match global {
+            Some(global) => println!("  {interface}: version {}", global.version),
+            None => println!("  {interface}: not supported"),
+        }
+    }
+
+    let seats = globals
+        .iter()
+        .filter(|global| global.interface == WlSeat::interface().name)
+        .map(|global| {
+            let seat: WlSeat = global_list.registry().bind(
+                global.name,
+                global.version.min(MAX_SEAT_VERSION),
+                &queue.handle(),
+                Mutex::new(None::<String>),
+            );
+            (seat, global.version)
+        })
+        .collect::<Vec<_>>();
+    // The names are sent right after binding.
+    queue
+        .roundtrip(&mut Doctor)
+        .wrap_err("getting the seat names")?;
+
+    println!("Seats:");
+    if seats.is_empty() {
+        println!("  none");
+    }
+    for (seat, version) in &seats {
+        let name = seat
+            .data::<Mutex<Option<String>>>()
+            .and_then(|name| name.lock().unwrap().clone())
+            .unwrap_or_else(|| "unnamed seat".to_owned());
+        println!("  {name}: version {version}");
+    }
+
+    let socket_path = clippyboard_shared::socket_path();
+    if socket_path.is_fallback {
+        println!("XDG_RUNTIME_DIR: not set, using a socket in /tmp");
+    }
+    match clippyboard_shared::ping(&socket_path.path) {
+        Ok(()) => println!("Daemon: reachable at {}", socket_path.path.display()),
+        Err(err) => println!("Daemon: not reachable: {err:#}"),
+    }
+
+    if ext.is_none() && wlr.is_none() {
+        return Err(eyre::eyre!(
+            "check https://wayland.app/protocols/ext-data-control-v1#compositor-support"
+        ))
+        .wrap_err(Failure::ProtocolUnsupported);
+    }
+    if ext.is_none() {
+        println!("Using the older wlr-data-control protocol, ext-data-control is preferred");
+    }
+
+    Ok(())
+}
+
+impl Dispatch<WlRegistry, GlobalListContents> for Doctor {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlRegistry,
+        _event: <WlRegistry as Proxy>::Event,
+        _data: &GlobalListContents,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        // the globals are only read once, changes don't matter
+    }
+}
+
+impl Dispatch<WlSeat, Mutex<Option<String>>> for Doctor {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlSeat,
+        event: <WlSeat as Proxy>::Event,
+        data: &Mutex<Option<String>>,
+        _conn: &Connection,
+        _qhandle: &QueueHandle<Self>,
+    ) {
+        if let wayland_client::protocol::wl_seat::Event::Name { name } = event {
+            *data.lock().unwrap() = Some(name);
+        }
+    }
+}
@@ -0,0 +1,620 @@
+use clippyboard_shared::HistoryItem;
+use clippyboard_shared::config::{DaemonConfig, DedupMode};
+use eyre::{Context, ContextCompat};
+use rustix::event::{PollFd, PollFlags, Timespec};
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufReader, Cursor, ErrorKind, Read};
+use std::os::fd::AsFd;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, atomic::AtomicU64, mpsc};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+pub const MAX_ENTRY_SIZE: u64 = 50_000_000;
+pub const MAX_HISTORY_BYTE_SIZE: usize = 100_000_000;
+/// Width and height that thumbnails fit into.
+pub const THUMBNAIL_SIZE: u32 = 128;
+
+pub struct History {
+    pub config: DaemonConfig,
+    next_item_id: AtomicU64,
+    /// Oldest first. A deque, since old items are evicted from the front while new ones are
+    /// pushed to the back, and copied items are usually close to either end.
+    pub items: Mutex<VecDeque<HistoryItem>>,
+    /// Notified with the ID of every newly stored item.
+    subscribers: Mutex<Vec<mpsc::Sender<u64>>>,
+    /// The items before the last clear and when it happened, see [`undo_clear`].
+    pub(crate) cleared: Mutex<Option<(Instant, VecDeque<HistoryItem>)>>,
+    /// Hash and MIME type of the last stored item if the limits evicted it right away, because
+    /// it's too big on its own. The duplicate check can't find it in `items` anymore, so without
+    /// this, every repeated offer of it would be stored and evicted again.
+    evicted_last_offer: Mutex<Option<(u64, String)>>,
+    /// Thumbnails of images stored without one, by item ID, created when the list is first read.
+    /// `None` for images that can't be decoded, so they aren't tried again.
+    thumbnail_cache: Mutex<HashMap<u64, Option<Vec<u8>>>>,
+    /// The file written by the last copy as a file, removed by the next one.
+    pub(crate) copied_file: Mutex<Option<PathBuf>>,
+}
+
+impl History {
+    pub fn new(config: DaemonConfig) -> Self {
+        Self {
+            config,
+            next_item_id: AtomicU64::new(0),
+            items: Mutex::new(VecDeque::new()),
+            subscribers: Mutex::new(Vec::new()),
+            cleared: Mutex::new(None),
+            evicted_last_offer: Mutex::new(None),
+            thumbnail_cache: Mutex::new(HashMap::new()),
+            copied_file: Mutex::new(None),
+        }
+    }
+
+    /// Returns a receiver for the IDs of all items stored from now on.
+    pub fn subscribe(&self) -> mpsc::Receiver<u64> {
+        let (send, recv) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(send);
+        recv
+    }
+}
+
+/// Whether `mime` is `text/plain` with a charset parameter that is compatible with UTF-8,
+/// like the `text/plain;charset=utf-8` that many apps offer instead of the bare `text/plain`.
+pub fn is_utf8_text_mime(mime: &str) -> bool {
+    let mut parts = mime.split(';').map(str::trim);
+    if !parts
+        .next()
+        .is_some_and(|base| base.eq_ignore_ascii_case("text/plain"))
+    {
+        return false;
+    }
+    parts.all(|param| match param.split_once('=') {
+        Some((name, value)) if name.trim().eq_ignore_ascii_case("charset") => {
+            let charset = value.trim().trim_matches('"');
+            ["utf-8", "utf8", "us-ascii"]
+                .iter()
+                .any(|utf8| charset.eq_ignore_ascii_case(utf8))
+        }
+        _ => true,
+    })
+}
+
+/// Whether clipboard entries offering `mime` aren't stored, see `ignored_mime_types` and
+/// `ignored_mime_patterns`.
+pub fn is_ignored_mime(config: &DaemonConfig, mime: &str) -> bool {
+    config
+        .ignored_mime_types
+        .iter()
+        .any(|ignored| ignored == mime)
+        || config
+            .ignored_mime_patterns
+            .iter()
+            .any(|pattern| matches_pattern(pattern, mime))
+}
+
+/// Matches `text` against a pattern where `*` matches any number of characters.
+pub fn matches_pattern(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts = parts.peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.is_empty()
+}
+
+/// Offered by every source of the daemon, so that its own selections aren't stored again when
+/// they come back to it.
+pub const OWN_SOURCE_MIME: &str = "application/x-clippyboard-source";
+
+/// MIME types that only some applications offer next to the content, and the application.
+const SOURCE_MIME_MARKERS: &[(&str, &str)] = &[
+    ("chromium/x-source-url", "Chromium"),
+    ("chromium/x-internal-source-rfh-token", "Chromium"),
+    ("text/x-moz-url-priv", "Firefox"),
+    ("text/_moz_htmlcontext", "Firefox"),
+    ("x-special/gnome-copied-files", "GNOME Files"),
+    ("application/x-kde-cutselection", "KDE"),
+    ("application/x-qt-image", "Qt"),
+];
+
+/// Guesses which application offers a selection from its MIME types. The protocols don't say,
+/// so this only knows applications that offer their own MIME types next to the content.
+pub fn source_from_mime_types<'a>(
+    mime_types: impl IntoIterator<Item = &'a str>,
+) -> Option<&'static str> {
+    let mime_types = mime_types.into_iter().collect::<Vec<_>>();
+    SOURCE_MIME_MARKERS
+        .iter()
+        .find(|(marker, _)| mime_types.contains(marker))
+        .map(|&(_, source)| source)
+}
+
+/// A reader that fails with [`ErrorKind::TimedOut`] when no data arrives for `timeout`,
+/// so that a source that never finishes writing can't block a reading thread forever.
+pub struct StallTimeout<R> {
+    pub inner: R,
+    pub timeout: Duration,
+}
+
+impl<R: Read + AsFd> Read for StallTimeout<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let timeout = Timespec::try_from(self.timeout).map_err(std::io::Error::other)?;
+        let mut fds = [PollFd::new(&self.inner, PollFlags::IN)];
+        let ready = rustix::event::poll(&mut fds, Some(&timeout))?;
+        if ready == 0 {
+            return Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                format!("no data for {:?}", self.timeout),
+            ));
+        }
+        self.inner.read(buf)
+    }
+}
+
+pub fn read_fd_into_history(
+    history: &History,
+    time: Duration,
+    mime: String,
+    seat: Option<String>,
+    source: Option<String>,
+    data_reader: impl Read,
+) -> Result<(), eyre::Error> {
+    let mut data_reader = BufReader::new(data_reader).take(MAX_ENTRY_SIZE);
+    let mut data = Vec::new();
+    loop {
+        // read_to_end keeps what it has read so far when it fails, so we can just continue.
+        match data_reader.read_to_end(&mut data) {
+            Ok(_) => break,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => {
+                return Err(err).wrap_err_with(|| {
+                    format!("reading content data, failed after {} bytes", data.len())
+                });
+            }
+        }
+    }
+
+    let created_time = u64::try_from(time.as_millis()).unwrap();
+    insert_item_from_seat(history, mime, data.into(), created_time, seat, source)
+}
+
+/// Inserts a new item that didn't come from a seat, see [`insert_item_from_seat`].
+pub fn insert_item(
+    history: &History,
+    mime: String,
+    data: Arc<[u8]>,
+    created_time: u64,
+) -> Result<(), eyre::Error> {
+    insert_item_from_seat(history, mime, data, created_time, None, None)
+}
+
+/// Inserts a new item into the history, applying deduplication and eviction.
+/// Every new item goes through here, no matter where it came from.
+pub fn insert_item_from_seat(
+    history: &History,
+    mime: String,
+    data: Arc<[u8]>,
+    created_time: u64,
+    seat: Option<String>,
+    source: Option<String>,
+) -> Result<(), eyre::Error> {
+    // Text is always stored as `text/plain`, so that it's offered with all the text aliases
+    // when it's copied again, no matter which variant it was offered as.
+    let (mime, original_mime) = if mime != "text/plain" && is_utf8_text_mime(&mime) {
+        ("text/plain".to_owned(), Some(mime))
+    } else {
+        (mime, None)
+    };
+
+    let (mime, data) = match history.config.max_image_dimension {
+        Some(max_dimension) if mime.starts_with("image/") => {
+            match downscale_image(&data, max_dimension) {
+                Some(png) => ("image/png".to_owned(), png.into()),
+                None => (mime, data),
+            }
+        }
+        _ => (mime, data),
+    };
+
+    // The data is stored as-is, trimming is only used to decide whether to store it.
+    if history.config.skip_blank_text
+        && mime == "text/plain"
+        && str::from_utf8(&data).is_ok_and(|text| text.trim().is_empty())
+    {
+        info!(
+            %mime,
+            bytes = data.len(),
+            "Skipping store of new item because it only contains whitespace"
+        );
+        return Ok(());
+    }
+    if !history.config.store_images && mime.starts_with("image/") {
+        info!(
+            %mime,
+            bytes = data.len(),
+            "Skipping store of new item because storing images is turned off"
+        );
+        return Ok(());
+    }
+    if mime == "text/plain" && data.len() < history.config.min_text_bytes {
+        info!(
+            %mime,
+            bytes = data.len(),
+            "Skipping store of new item because it is shorter than min_text_bytes"
+        );
+        return Ok(());
+    }
+
+    let thumbnail = if history.config.image_thumbnails && mime.starts_with("image/") {
+        thumbnail(&data)
+    } else {
+        None
+    };
+
+    let new_entry = HistoryItem {
+        id: history
+            .next_item_id
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+        mime: mime.clone(),
+        hash: xxhash_rust::xxh3::xxh3_64(&data),
+        data,
+        created_time,
+        copy_count: 0,
+        seat,
+        original_mime,
+        tags: Vec::new(),
+        thumbnail,
+        source,
+    };
+    remove_expired_items(history, created_time);
+
+    let entry_id = new_entry.id;
+    let bytes = new_entry.data.len();
+    let new_entry_hash = new_entry.hash;
+
+    let mut evicted_last_offer = history.evicted_last_offer.lock().unwrap();
+    if let Some((hash, evicted_mime)) = &*evicted_last_offer
+        && *hash == new_entry.hash
+        && (*evicted_mime == mime || history.config.dedup == DedupMode::IgnoreMime)
+    {
+        info!(
+            %mime,
+            bytes,
+            "Skipping store of new item because it is identical to the last one, which was evicted for being too big"
+        );
+        return Ok(());
+    }
+
+    let mut items = history.items.lock().unwrap();
+    if let Some(last) = items.back_mut()
+        && last.hash == new_entry.hash
+        && last.data == new_entry.data
+    {
+        match history.config.dedup {
+            DedupMode::Exact if last.mime != new_entry.mime => {}
+            DedupMode::Exact => {
+                info!(
+                    %mime,
+                    bytes,
+                    entry_id = last.id,
+                    "Skipping store of new item because it is identical to last one"
+                );
+                return Ok(());
+            }
+            DedupMode::IgnoreMime => {
+                info!(
+                    %mime,
+                    bytes,
+                    entry_id = last.id,
+                    "Skipping store of new item because it has the same data as the last one"
+                );
+                last.mime = new_entry.mime;
+                return Ok(());
+            }
+        }
+    }
+
+    if let Some(last) = items.back()
+        && should_merge(&history.config, last, &new_entry)
+    {
+        info!(
+            entry_id = last.id,
+            "Replacing previous item because the new item extends it"
+        );
+        items.pop_back();
+    }
+
+    items.push_back(new_entry);
+    // Undoing a clear would drop the new item.
+    *history.cleared.lock().unwrap() = None;
+
+    let quotas = [
+        ("text/", history.config.max_text_bytes),
+        ("image/", history.config.max_image_bytes),
+        ("", Some(MAX_HISTORY_BYTE_SIZE)),
+    ];
+    for (mime_prefix, limit) in quotas {
+        if let Some(limit) = limit {
+            evict_over_limit(&history.config, &mut items, mime_prefix, limit);
+        }
+    }
+    *evicted_last_offer = match items.back() {
+        Some(last) if last.id == entry_id => None,
+        _ => Some((new_entry_hash, mime.clone())),
+    };
+    drop(evicted_last_offer);
+
+    info!(
+        %mime,
+        bytes,
+        entry_id,
+        total_bytes = items.iter().map(item_size).sum::<usize>(),
+        "Successfully stored clipboard value"
+    );
+    drop(items);
+
+    history
+        .subscribers
+        .lock()
+        .unwrap()
+        .retain(|subscriber| subscriber.send(entry_id).is_ok());
+    Ok(())
+}
+
+/// Images in one of these formats are also offered in the others, transcoded when they are pasted,
+/// for apps that only accept one format.
+pub const TRANSCODABLE_IMAGE_MIMES: &[&str] = &["image/png", "image/jpeg", "image/jpg"];
+
+fn image_format(mime: &str) -> Option<image::ImageFormat> {
+    match mime {
+        "image/png" => Some(image::ImageFormat::Png),
+        "image/jpeg" | "image/jpg" => Some(image::ImageFormat::Jpeg),
+        _ => None,
+    }
+}
+
+/// Returns the image re-encoded as `to_mime`, or `None` if it's in that format already.
+pub fn transcode_image(
+    data: &[u8],
+    from_mime: &str,
+    to_mime: &str,
+) -> eyre::Result<Option<Vec<u8>>> {
+    let from = image_format(from_mime).wrap_err_with(|| format!("can't decode {from_mime}"))?;
+    let to = image_format(to_mime).wrap_err_with(|| format!("can't encode {to_mime}"))?;
+    if from == to {
+        return Ok(None);
+    }
+
+    let image = image::load_from_memory_with_format(data, from).wrap_err("decoding image")?;
+    // JPEG has no transparency.
+    let image = match to {
+        image::ImageFormat::Jpeg => image::DynamicImage::ImageRgb8(image.to_rgb8()),
+        _ => image,
+    };
+    let mut encoded = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut encoded), to)
+        .wrap_err("encoding image")?;
+    Ok(Some(encoded))
+}
+
+/// Scales the image down to fit into `max_dimension` pixels, keeping its aspect ratio, and
+/// encodes it as PNG. Returns `None` if it's small enough already or can't be decoded.
+fn downscale_image(data: &[u8], max_dimension: u32) -> Option<Vec<u8>> {
+    let image = match image::load_from_memory(data) {
+        Ok(image) => image,
+        Err(err) => {
+            warn!("Failed to decode image, storing it as-is: {err}");
+            return None;
+        }
+    };
+    if image.width() <= max_dimension && image.height() <= max_dimension {
+        return None;
+    }
+
+    let scaled = image.resize(
+        max_dimension,
+        max_dimension,
+        image::imageops::FilterType::Triangle,
+    );
+    let png = match encode_png(&scaled) {
+        Ok(png) => png,
+        Err(err) => {
+            warn!("Failed to encode downscaled image, storing the original: {err}");
+            return None;
+        }
+    };
+    info!(
+        width = image.width(),
+        height = image.height(),
+        new_width = scaled.width(),
+        new_height = scaled.height(),
+        "Downscaled image"
+    );
+    Some(png)
+}
+
+/// Returns the thumbnail of an image item for the history list, creating it on the first call,
+/// since decoding the image is slow and sending the whole image for the list is slower.
+/// Uses the stored thumbnail if there is one.
+pub fn list_thumbnail(history: &History, item: &HistoryItem) -> Option<Vec<u8>> {
+    if item.thumbnail.is_some() || !item.mime.starts_with("image/") {
+        return item.thumbnail.clone();
+    }
+    if let Some(thumbnail) = history.thumbnail_cache.lock().unwrap().get(&item.id) {
+        return thumbnail.clone();
+    }
+    // Not holding the lock while decoding, two readers creating the same thumbnail is harmless.
+    let created = thumbnail(&item.data);
+    history
+        .thumbnail_cache
+        .lock()
+        .unwrap()
+        .insert(item.id, created.clone());
+    created
+}
+
+/// Forgets the cached thumbnails of items that are no longer in the history.
+pub fn prune_thumbnail_cache(history: &History, ids: &[u64]) {
+    history
+        .thumbnail_cache
+        .lock()
+        .unwrap()
+        .retain(|id, _| ids.contains(id));
+}
+
+/// Returns a PNG of the image that fits into [`THUMBNAIL_SIZE`] pixels.
+fn thumbnail(data: &[u8]) -> Option<Vec<u8>> {
+    let image = image::load_from_memory(data).ok()?;
+    match encode_png(&image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)) {
+        Ok(png) => Some(png),
+        Err(err) => {
+            warn!("Failed to encode thumbnail: {err}");
+            None
+        }
+    }
+}
+
+fn encode_png(image: &image::DynamicImage) -> image::ImageResult<Vec<u8>> {
+    let mut png = Vec::new();
+    image.write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)?;
+    Ok(png)
+}
+
+/// The bytes an item is counted with for the history limits.
+fn item_size(item: &HistoryItem) -> usize {
+    item.data.len()
+        + item.thumbnail.as_ref().map_or(0, Vec::len)
+        + std::mem::size_of::<HistoryItem>()
+}
+
+/// Whether the item is never evicted, expired, cleared or deleted as part of a range.
+pub(crate) fn is_kept(config: &DaemonConfig, item: &HistoryItem) -> bool {
+    config.keep_tagged && !item.tags.is_empty()
+}
+
+/// Removes the oldest items with a MIME type starting with `mime_prefix` until
+/// all of them together take up at most `limit` bytes. Other items are left alone.
+fn evict_over_limit(
+    config: &DaemonConfig,
+    items: &mut VecDeque<HistoryItem>,
+    mime_prefix: &str,
+    limit: usize,
+) {
+    // Kept items don't count, otherwise they could take up the whole limit and every
+    // new item would be evicted right away.
+    let matches = |item: &HistoryItem| item.mime.starts_with(mime_prefix) && !is_kept(config, item);
+    let mut total = items
+        .iter()
+        .filter(|item| matches(item))
+        .map(item_size)
+        .sum::<usize>();
+
+    let old_len = items.len();
+    // oldest first
+    items.retain(|item| {
+        if total > limit && matches(item) {
+            total -= item_size(item);
+            false
+        } else {
+            true
+        }
+    });
+
+    if items.len() != old_len {
+        info!(
+            dropped_count = old_len - items.len(),
+            mime_prefix,
+            limit_bytes = limit,
+            "Dropping old items because the byte limit was reached"
+        );
+    }
+}
+
+/// Whether `new` is just a grown version of the `previous` text selection.
+fn should_merge(config: &DaemonConfig, previous: &HistoryItem, new: &HistoryItem) -> bool {
+    let Some(window) = config.merge_text_within_ms else {
+        return false;
+    };
+    if previous.mime != "text/plain"
+        || new.mime != "text/plain"
+        || new.created_time.saturating_sub(previous.created_time) > window
+        // It has been explicitly chosen by the user, so it is not an accident.
+        || previous.copy_count > 0
+        || !previous.tags.is_empty()
+        || previous.data.is_empty()
+    {
+        return false;
+    }
+
+    // Selections are extended at one of the ends.
+    new.data.starts_with(&previous.data) || new.data.ends_with(&previous.data)
+}
+
+/// Restores the items from before the last clear, if it was within `undo_clear_secs` and
+/// nothing was stored since. Returns `None` if there is nothing to undo, and otherwise the
+/// restored most recent item, which was on the clipboard.
+pub fn undo_clear(history: &History) -> Option<Option<HistoryItem>> {
+    let (time, items) = history.cleared.lock().unwrap().take()?;
+    if time.elapsed() > Duration::from_secs(history.config.undo_clear_secs) {
+        return None;
+    }
+
+    let current = items.back().cloned();
+    info!(count = items.len(), "Undoing clear");
+    *history.items.lock().unwrap() = items;
+    Some(current)
+}
+
+/// Removes the item with that ID, returning whether it existed.
+pub fn remove_item(history: &History, id: u64) -> bool {
+    let mut items = history.items.lock().unwrap();
+    let old_len = items.len();
+    items.retain(|item| item.id != id);
+    items.len() != old_len
+}
+
+/// Adds the tag to the item or removes it from it, returning whether the item exists.
+pub fn set_tag(history: &History, id: u64, tag: &str, tagged: bool) -> bool {
+    let mut items = history.items.lock().unwrap();
+    let Some(item) = items.iter_mut().find(|item| item.id == id) else {
+        return false;
+    };
+    if !tagged {
+        item.tags.retain(|existing| existing != tag);
+    } else if !item.tags.iter().any(|existing| existing == tag) {
+        item.tags.push(tag.to_owned());
+    }
+    true
+}
+
+/// Removes items older than the configured maximum age.
+/// `now` is in milliseconds since the UNIX epoch, like `created_time`.
+pub fn remove_expired_items(history: &History, now: u64) {
+    let Some(max_age_secs) = history.config.max_age_secs else {
+        return;
+    };
+    let cutoff = now.saturating_sub(max_age_secs.saturating_mul(1000));
+
+    let mut items = history.items.lock().unwrap();
+    let old_len = items.len();
+    items.retain(|item| item.created_time >= cutoff || is_kept(&history.config, item));
+    if items.len() != old_len {
+        info!(
+            dropped_count = old_len - items.len(),
+            max_age_secs, "Dropping items because they are too old"
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! A read-only HTTP endpoint on localhost, for looking at the history from another machine
+//! through an SSH tunnel. Only built with the `http` feature and only started when
+//! `http_port` is configured.
+//!
+//! - `GET /history` returns a JSON array with the metadata of all items, oldest first.
+//! - `GET /items/{id}` returns the data of an item with its MIME type as the content type.
+//!
+//! Requests for any other `Host` than `127.0.0.1:{port}` or `localhost:{port}` are rejected, so that
+//! web pages can't read the history by pointing their own domain at 127.0.0.1 (DNS rebinding).
+
+use crate::history::History;
+use clippyboard_shared::HistoryItemMeta;
+use eyre::Context;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, TcpListener};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+/// Text previews in `/history` are cut off after this many characters.
+const PREVIEW_CHARS: usize = 200;
+const MAX_REQUEST_LINE: u64 = 8192;
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Listens on `127.0.0.1:{port}` and serves requests until the process exits.
+pub fn serve<S: Send + Sync + 'static>(
+    port: u16,
+    state: Arc<S>,
+    history: fn(&S) -> &History,
+) -> eyre::Result<()> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+        .wrap_err_with(|| format!("binding to 127.0.0.1:{port}"))?;
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            let _ = stream.set_read_timeout(Some(READ_TIMEOUT));
+            if let Err(err) = handle_connection(history(&state), port, stream) {
+                warn!("Failed to handle HTTP request: {err:?}");
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Answers a single request to the server on `port` and closes the connection.
+pub fn handle_connection(
+    history: &History,
+    port: u16,
+    stream: impl Read + Write,
+) -> eyre::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    (&mut reader)
+        .take(MAX_REQUEST_LINE)
+        .read_line(&mut request_line)
+        .wrap_err("reading request line")?;
+    let mut host = None;
+    loop {
+        let mut header = String::new();
+        let read = (&mut reader)
+            .take(MAX_REQUEST_LINE)
+            .read_line(&mut header)
+            .wrap_err("reading headers")?;
+        if read == 0 || header == "\r\n" || header == "\n" {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':')
+            && name.trim().eq_ignore_ascii_case("host")
+        {
+            host = Some(value.trim().to_owned());
+        }
+    }
+    let mut stream = reader.into_inner();
+
+    let allowed_hosts = [format!("127.0.0.1:{port}"), format!("localhost:{port}")];
+    if !host.is_some_and(|host| allowed_hosts.contains(&host.to_ascii_lowercase())) {
+        return respond(
+            &mut stream,
+            "403 Forbidden",
+            "text/plain",
+            b"unexpected host",
+        );
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return respond(&mut stream, "400 Bad Request", "text/plain", b"bad request");
+    };
+    if method != "GET" {
+        return respond(
+            &mut stream,
+            "405 Method Not Allowed",
+            "text/plain",
+            b"only GET is supported",
+        );
+    }
+
+    if path == "/history" {
+        let items = history
+            .items
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|item| HistoryItemMeta::new(item, PREVIEW_CHARS))
+            .collect::<Vec<_>>();
+        let json = serde_json::to_vec(&items).wrap_err("serializing items")?;
+        return respond(&mut stream, "200 OK", "application/json", &json);
+    }
+
+    if let Some(id) = path.strip_prefix("/items/")
+        && let Ok(id) = id.parse::<u64>()
+    {
+        let item = history
+            .items
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|item| item.id == id)
+            .map(|item| (item.mime.clone(), item.data.clone()));
+        if let Some((mime, data)) = item {
+            // The MIME type comes from the app that copied it, so it could contain more headers.
+            let content_type = if is_valid_mime(&mime) {
+                &mime
+            } else {
+                "application/octet-stream"
+            };
+            return respond(&mut stream, "200 OK", content_type, &data);
+        }
+    }
+
+    respond(&mut stream, "404 Not Found", "text/plain", b"not found")
+}
+
+/// Whether `mime` looks like `type/subtype`, optionally with parameters, and contains nothing
+/// that would end the header.
+fn is_valid_mime(mime: &str) -> bool {
+    let essence = mime.split(';').next().unwrap_or_default();
+    essence
+        .split_once('/')
+        .is_some_and(|(kind, subtype)| !kind.trim().is_empty() && !subtype.trim().is_empty())
+        && mime
+            .bytes()
+            .all(|byte| byte == b' ' || byte.is_ascii_graphic())
+}
+
+fn respond(
+    mut stream: impl Write,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> eyre::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nX-Content-Type-Options: nosniff\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )
+    .wrap_err("writing response")?;
+    stream.write_all(body).wrap_err("writing response")?;
+    stream.flush().wrap_err("writing response")
+}
@@ -0,0 +1,11 @@
+//! The parts of the daemon that don't need a Wayland connection.
+
+#[cfg(feature = "dbus")]
+pub mod dbus;
+pub mod history;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod peer;
+pub mod pool;
+pub mod seats;
+pub mod systemd;
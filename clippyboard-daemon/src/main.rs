@@ -1,68 +1,377 @@
-use clippyboard_shared::HistoryItem;
+use clippyboard_daemon::history::{self, History};
+use clippyboard_daemon::peer::{self, Clipboard};
+use clippyboard_daemon::pool::WorkerPool;
+use clippyboard_daemon::seats;
+use clippyboard_daemon::systemd;
+use clippyboard_shared::SocketPath;
+use clippyboard_shared::config::Config;
+use clippyboard_shared::exit::Failure;
 use eyre::Context;
 use eyre::ContextCompat;
 use eyre::bail;
 use rustix::event::PollFd;
 use rustix::event::PollFlags;
+use rustix::event::Timespec;
 use rustix::fs::OFlags;
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::convert::Infallible;
+use std::collections::VecDeque;
 use std::io;
 use std::io::ErrorKind;
 use std::io::PipeReader;
-use std::io::{BufReader, BufWriter, PipeWriter, Read, Write};
+use std::io::{BufWriter, PipeWriter, Read, Write};
 use std::os::fd::AsFd;
+use std::os::unix::fs::PermissionsExt;
 use std::os::unix::net::{UnixListener, UnixStream};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
-use std::sync::{Arc, Mutex, OnceLock, atomic::AtomicU64};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use std::time::Instant;
 use std::time::SystemTime;
 use tracing::error;
 use tracing::info;
 use tracing::warn;
 use tracing_subscriber::EnvFilter;
 use wayland_client::EventQueue;
+use wayland_client::protocol::wl_callback::WlCallback;
 use wayland_client::protocol::wl_registry::WlRegistry;
 use wayland_client::protocol::wl_seat::WlSeat;
-use wayland_client::{Dispatch, Proxy, QueueHandle, event_created_child};
-use wayland_protocols::ext::data_control::v1::client::ext_data_control_device_v1;
-use wayland_protocols::ext::data_control::v1::client::ext_data_control_device_v1::{
-    EVT_DATA_OFFER_OPCODE, ExtDataControlDeviceV1,
-};
+use wayland_client::{Dispatch, Proxy, QueueHandle};
 use wayland_protocols::ext::data_control::v1::client::ext_data_control_manager_v1::ExtDataControlManagerV1;
-use wayland_protocols::ext::data_control::v1::client::ext_data_control_offer_v1;
-use wayland_protocols::ext::data_control::v1::client::ext_data_control_offer_v1::ExtDataControlOfferV1;
-use wayland_protocols::ext::data_control::v1::client::ext_data_control_source_v1;
-use wayland_protocols::ext::data_control::v1::client::ext_data_control_source_v1::ExtDataControlSourceV1;
+use wayland_protocols_wlr::data_control::v1::client::zwlr_data_control_manager_v1::ZwlrDataControlManagerV1;
 
-const MAX_ENTRY_SIZE: u64 = 50_000_000;
-const MAX_HISTORY_BYTE_SIZE: usize = 100_000_000;
+mod data_control;
+mod doctor;
+
+use data_control::{DataControlDevice, DataControlManager, DataControlOffer};
+
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+/// Threads for reading new entries and for sending entries each, so a burst of clipboard
+/// activity doesn't spawn a thread per entry.
+const WORKER_THREADS: usize = 8;
+
+/// The highest versions of the globals we know how to use. We bind the version the compositor
+/// advertises, up to these, so a newer compositor doesn't send events that we don't expect.
+/// wl_seat version 2 adds the name.
+const MAX_SEAT_VERSION: u32 = 2;
+const MAX_DATA_CONTROL_VERSION: u32 = 1;
+/// wlr-data-control version 2 adds the primary selection.
+const MAX_WLR_DATA_CONTROL_VERSION: u32 = 2;
 
 const MIME_TYPES: &[&str] = &["text/plain", "image/png", "image/jpg"];
 
+/// Offered by sources for content that was only set on the clipboard, not copied by the user,
+/// so that we don't store it.
+const TRANSIENT_MIME: &str = "application/x-clippyboard-transient";
+
 struct SharedState {
-    next_item_id: AtomicU64,
-    items: Mutex<Vec<HistoryItem>>,
+    history: History,
+    /// Notified after every handled clipboard selection when running with `--oneshot`.
+    capture_done: Option<mpsc::Sender<()>>,
     notify_write_send: PipeWriter,
-
-    data_control_manager: OnceLock<ExtDataControlManagerV1>,
-    data_control_devices: Mutex<HashMap</*seat global name */ u32, ExtDataControlDeviceV1>>,
+    /// Reads new clipboard entries. Separate from `send_pool`, since reading our own
+    /// selection needs a free thread for sending it.
+    capture_pool: WorkerPool,
+    /// Sends our selection to pasting clients.
+    send_pool: WorkerPool,
+    /// Stops the daemon, see `main_inner`.
+    exit_send: mpsc::Sender<eyre::Result<()>>,
+
+    /// The manager may disappear and reappear at runtime, in which case we have to re-grab all devices.
+    data_control_manager: Mutex<Option<Box<dyn DataControlManager>>>,
+    data_control_devices: Mutex<HashMap</*seat global name */ u32, Box<dyn DataControlDevice>>>,
     qh: QueueHandle<WlState>,
 }
 
+#[derive(Default)]
+enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors like journald or Loki.
+    Json,
+}
+
+#[derive(Default)]
+pub struct Args {
+    oneshot: Option<Oneshot>,
+    log_format: LogFormat,
+    /// Report what the compositor supports instead of running, see the `doctor` module.
+    doctor: bool,
+    allow_root: bool,
+}
+
+/// Exit after a number of clipboard events or some time, printing the history as JSON.
+/// Useful for testing against a real (nested) compositor.
+#[derive(Default)]
+pub struct Oneshot {
+    max_events: Option<u64>,
+    timeout: Option<Duration>,
+}
+
+const USAGE: &str = "\
+Usage: clippyboard-daemon [OPTIONS]
+       clippyboard-daemon doctor
+
+Stores the clipboard history and serves it on the socket.
+The doctor command reports what the compositor supports instead.
+
+Options:
+      --oneshot <EVENTS>           Exit after this many clipboard events, printing the history as JSON
+      --oneshot-timeout <SECONDS>  Exit after this many seconds, printing the history as JSON
+      --log-format <text|json>     Format of the logs on stderr
+      --allow-root                 Run even as root
+  -V, --version                    Print the version
+  -h, --help                       Print this help
+";
+
+fn parse_args() -> eyre::Result<Args> {
+    let mut parsed = Args::default();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--oneshot" => {
+                let events = args
+                    .next()
+                    .wrap_err("--oneshot requires a number of events")?
+                    .parse()
+                    .wrap_err("invalid number of events for --oneshot")?;
+                parsed.oneshot.get_or_insert_default().max_events = Some(events);
+            }
+            "--oneshot-timeout" => {
+                let seconds = args
+                    .next()
+                    .wrap_err("--oneshot-timeout requires a number of seconds")?
+                    .parse()
+                    .wrap_err("invalid number of seconds for --oneshot-timeout")?;
+                parsed.oneshot.get_or_insert_default().timeout = Some(Duration::from_secs(seconds));
+            }
+            "--log-format" => {
+                parsed.log_format = match args.next().as_deref() {
+                    Some("text") => LogFormat::Text,
+                    Some("json") => LogFormat::Json,
+                    _ => bail!("--log-format requires either text or json"),
+                };
+            }
+            "doctor" => parsed.doctor = true,
+            "--allow-root" => parsed.allow_root = true,
+            "--help" | "-h" => {
+                print!("{USAGE}");
+                std::process::exit(0);
+            }
+            "--version" | "-V" => {
+                println!("{}", clippyboard_shared::version!());
+                std::process::exit(0);
+            }
+            _ => bail!("unknown argument {arg}, see --help"),
+        }
+    }
+
+    Ok(parsed)
+}
+
 struct InProgressOffer {
     mime_types: Mutex<HashSet<String>>,
     time: Duration,
 }
 
+/// User data of every wl_seat.
+struct SeatData {
+    /// Key of the seat's device in `data_control_devices`.
+    global_name: u32,
+    /// Only known after the name event, which needs version 2.
+    name: Mutex<Option<String>>,
+    /// Whether the seat already got a new device after one was finished while the seat stayed.
+    regrabbed: AtomicBool,
+}
+
+impl WlState {
+    /// Starts monitoring the selection of the seat, as soon as there is a data control manager.
+    fn add_seat(&mut self, seat: WlSeat, qhandle: &QueueHandle<Self>) {
+        let manager = self.shared_state.data_control_manager.lock().unwrap();
+        match &*manager {
+            None => {
+                self.deferred_seats.push(seat);
+            }
+            Some(manager) => {
+                let global_name = seat_data(&seat).global_name;
+                let device = manager.get_data_device(&seat, self.next_device_generation, qhandle);
+                self.next_device_generation += 1;
+                self.shared_state
+                    .data_control_devices
+                    .lock()
+                    .unwrap()
+                    .insert(global_name, device);
+            }
+        }
+    }
+
+    /// Starts using a newly bound manager, replacing the current one.
+    fn set_data_control_manager(
+        &mut self,
+        manager: Box<dyn DataControlManager>,
+        name: u32,
+        qhandle: &QueueHandle<Self>,
+    ) {
+        if self.data_control_manager_name.is_some() {
+            self.release_data_control_manager();
+        }
+
+        for seat in self.deferred_seats.drain(..) {
+            let device = manager.get_data_device(&seat, self.next_device_generation, qhandle);
+            self.next_device_generation += 1;
+            self.shared_state
+                .data_control_devices
+                .lock()
+                .unwrap()
+                .insert(seat_data(&seat).global_name, device);
+        }
+
+        *self.shared_state.data_control_manager.lock().unwrap() = Some(manager);
+        self.data_control_manager_name = Some(name);
+    }
+
+    /// Destroys the manager and all devices, keeping the seats around to grab new devices later.
+    fn release_data_control_manager(&mut self) {
+        self.data_control_manager_name = None;
+        if let Some(manager) = self
+            .shared_state
+            .data_control_manager
+            .lock()
+            .unwrap()
+            .take()
+        {
+            manager.destroy();
+        }
+
+        let mut devices = self.shared_state.data_control_devices.lock().unwrap();
+        for (_, device) in devices.drain() {
+            self.deferred_seats.push(device.seat().clone());
+            device.destroy();
+        }
+    }
+
+    /// Binds the wlr-data-control manager, which is only used without an ext-data-control one.
+    fn bind_wlr_data_control_manager(
+        &mut self,
+        registry: &WlRegistry,
+        qhandle: &QueueHandle<Self>,
+    ) {
+        let Some((name, version)) = self.wlr_data_control_global else {
+            return;
+        };
+        info!(version, "Binding zwlr_data_control_manager_v1");
+        let manager: ZwlrDataControlManagerV1 = registry.bind(name, version, qhandle, ());
+        self.set_data_control_manager(Box::new(manager), name, qhandle);
+    }
+
+    fn on_device_finished(
+        &mut self,
+        seat: &WlSeat,
+        conn: &wayland_client::Connection,
+        qhandle: &QueueHandle<Self>,
+    ) {
+        warn!("data control device was finished");
+        self.shared_state
+            .data_control_devices
+            .lock()
+            .unwrap()
+            .remove(&seat_data(seat).global_name);
+        // This usually happens when the seat goes away, but the compositor may announce that
+        // after finishing the device. Once the sync is done, we know whether the seat is still there.
+        conn.display().sync(qhandle, FinishedSeat(seat.clone()));
+    }
+}
+
+impl WlState {
+    fn on_selection(&mut self, offer: Box<dyn DataControlOffer>, seat: &WlSeat) {
+        match self.burst_deadline() {
+            None => capture_offer(self, offer, seat),
+            Some(deadline) => {
+                let previous = self.pending_capture.replace(PendingCapture {
+                    offer,
+                    seat: seat.clone(),
+                    deadline,
+                });
+                if let Some(previous) = previous {
+                    info!(
+                        "Dropping clipboard entry because it was replaced during a burst of copies"
+                    );
+                    previous.offer.destroy();
+                }
+            }
+        }
+    }
+
+    /// Returns when to capture the new selection if it's part of a burst of more than
+    /// `capture_burst_limit` selections, in which case it's only captured if no other
+    /// selection follows it until then.
+    fn burst_deadline(&mut self) -> Option<Instant> {
+        let config = &self.shared_state.history.config;
+        let limit = config.capture_burst_limit?;
+        let window = Duration::from_millis(config.capture_burst_window_ms);
+
+        let now = Instant::now();
+        self.recent_selections
+            .retain(|time| now.duration_since(*time) < window);
+        self.recent_selections.push_back(now);
+        // Once a burst started, everything waits, so that the captures stay in order.
+        if self.recent_selections.len() <= limit && self.pending_capture.is_none() {
+            return None;
+        }
+        Some(now + window)
+    }
+
+    /// Captures the last selection of a burst if the burst is over.
+    fn capture_pending_if_due(&mut self) {
+        if self
+            .pending_capture
+            .as_ref()
+            .is_some_and(|pending| pending.deadline <= Instant::now())
+        {
+            let pending = self.pending_capture.take().unwrap();
+            capture_offer(self, pending.offer, &pending.seat);
+        }
+    }
+}
+
+fn seat_data(seat: &WlSeat) -> &SeatData {
+    seat.data::<SeatData>()
+        .expect("missing SeatData for WlSeat")
+}
+
 struct WlState {
     shared_state: Arc<SharedState>,
 
     /// wl_seat that arrived before the data control manager so we weren't able to grab their device immediatly.
+    /// This also contains seats whose device was dropped because the manager went away.
     deferred_seats: Vec<WlSeat>,
+    /// Global name of the currently bound data control manager, to notice its removal.
+    data_control_manager_name: Option<u32>,
+    /// Name and version of the zwlr_data_control_manager_v1 global, if there is one.
+    /// It's only bound while there is no ext_data_control_manager_v1.
+    wlr_data_control_global: Option<(u32, u32)>,
+    /// Global names of the advertised wl_seats, to tell whether a finished device's seat is still there.
+    seat_globals: HashSet<u32>,
+    /// When the selections within the `capture_burst_window_ms` arrived.
+    recent_selections: VecDeque<Instant>,
+    /// The last selection of a burst, captured once the burst is over.
+    pending_capture: Option<PendingCapture>,
+    /// The generation of the next device, see [`DataControlDevice::generation`].
+    next_device_generation: u64,
+}
+
+/// User data of the sync request sent when a seat's device was finished.
+struct FinishedSeat(WlSeat);
+
+struct PendingCapture {
+    offer: Box<dyn DataControlOffer>,
+    seat: WlSeat,
+    /// When it's captured if no other selection arrives until then.
+    deadline: Instant,
 }
 
 impl Dispatch<WlRegistry, ()> for WlState {
@@ -78,269 +387,491 @@ impl Dispatch<WlRegistry, ()> for WlState {
             wayland_client::protocol::wl_registry::Event::Global {
                 name,
                 interface,
-                version: _, // we only need version 1
+                version,
             } => {
                 if interface == WlSeat::interface().name {
-                    info!("A new seat was connected");
-                    let seat: WlSeat = proxy.bind(name, 1, qhandle, ());
-
-                    match state.shared_state.data_control_manager.get() {
-                        None => {
-                            state.deferred_seats.push(seat);
-                        }
-                        Some(manager) => {
-                            let device = manager.get_data_device(&seat, qhandle, ());
-                            state
-                                .shared_state
-                                .data_control_devices
-                                .lock()
-                                .unwrap()
-                                .insert(name, device);
-                        }
+                    let version = version.min(MAX_SEAT_VERSION);
+                    info!(version, "A new seat was connected");
+                    state.seat_globals.insert(name);
+                    let seat: WlSeat = proxy.bind(
+                        name,
+                        version,
+                        qhandle,
+                        SeatData {
+                            global_name: name,
+                            name: Mutex::new(None),
+                            regrabbed: AtomicBool::new(false),
+                        },
+                    );
+
+                    if state.shared_state.history.config.seats.is_empty() {
+                        state.add_seat(seat, qhandle);
+                    } else if version < 2 {
+                        warn!(
+                            "Ignoring a seat without a name because only some seats are monitored"
+                        );
                     }
+                    // otherwise, we wait for the name
                 } else if interface == ExtDataControlManagerV1::interface().name {
-                    let manager: ExtDataControlManagerV1 = proxy.bind(name, 1, qhandle, ());
-
-                    for seat in state.deferred_seats.drain(..) {
-                        let device = manager.get_data_device(&seat, qhandle, ());
-                        state
-                            .shared_state
-                            .data_control_devices
-                            .lock()
-                            .unwrap()
-                            .insert(name, device);
+                    let version = version.min(MAX_DATA_CONTROL_VERSION);
+                    info!(version, "Binding ext_data_control_manager_v1");
+                    if let Some(old_manager) =
+                        &*state.shared_state.data_control_manager.lock().unwrap()
+                    {
+                        info!(
+                            "Replacing {} with the new ext_data_control_manager_v1",
+                            old_manager.interface_name()
+                        );
+                    }
+                    let manager: ExtDataControlManagerV1 = proxy.bind(name, version, qhandle, ());
+                    state.set_data_control_manager(Box::new(manager), name, qhandle);
+                } else if interface == ZwlrDataControlManagerV1::interface().name {
+                    state.wlr_data_control_global =
+                        Some((name, version.min(MAX_WLR_DATA_CONTROL_VERSION)));
+                    if state.data_control_manager_name.is_none() {
+                        state.bind_wlr_data_control_manager(proxy, qhandle);
                     }
-
-                    state
-                        .shared_state
-                        .data_control_manager
-                        .set(manager)
-                        .expect("ext_data_control_manager_v1 already set, global appeared twice?");
                 }
             }
             wayland_client::protocol::wl_registry::Event::GlobalRemove { name } => {
-                // try to remove, if it's not a wl_seat it may not exist
-                state
-                    .shared_state
-                    .data_control_devices
-                    .lock()
-                    .unwrap()
-                    .remove(&name);
+                state.seat_globals.remove(&name);
+                if state
+                    .wlr_data_control_global
+                    .is_some_and(|(wlr_name, _)| wlr_name == name)
+                {
+                    state.wlr_data_control_global = None;
+                }
+
+                if state.data_control_manager_name == Some(name) {
+                    state.release_data_control_manager();
+                    if state.wlr_data_control_global.is_some() {
+                        warn!(
+                            "The data control manager was removed, falling back to zwlr_data_control_manager_v1"
+                        );
+                        state.bind_wlr_data_control_manager(proxy, qhandle);
+                    } else {
+                        warn!("The data control manager was removed, waiting for it to come back");
+                    }
+                } else {
+                    // try to remove, if it's not a wl_seat it may not exist
+                    let device = state
+                        .shared_state
+                        .data_control_devices
+                        .lock()
+                        .unwrap()
+                        .remove(&name);
+                    if let Some(device) = device {
+                        info!("A seat was disconnected");
+                        device.destroy();
+                    }
+                    state
+                        .deferred_seats
+                        .retain(|seat| seat_data(seat).global_name != name);
+                }
             }
             _ => {}
         }
     }
 }
-impl Dispatch<ExtDataControlManagerV1, ()> for WlState {
+impl Dispatch<WlSeat, SeatData> for WlState {
     fn event(
-        _state: &mut Self,
-        _proxy: &ExtDataControlManagerV1,
-        _event: <ExtDataControlManagerV1 as wayland_client::Proxy>::Event,
-        _data: &(),
+        state: &mut Self,
+        proxy: &WlSeat,
+        event: <WlSeat as wayland_client::Proxy>::Event,
+        data: &SeatData,
         _conn: &wayland_client::Connection,
-        _qhandle: &wayland_client::QueueHandle<Self>,
+        qhandle: &wayland_client::QueueHandle<Self>,
     ) {
-        // no events at the time of writing
+        // we only care about the name
+        if let wayland_client::protocol::wl_seat::Event::Name { name } = event {
+            let seats = &state.shared_state.history.config.seats;
+            let is_new = data.name.lock().unwrap().replace(name.clone()).is_none();
+            if !seats.is_empty() && is_new {
+                if seats.contains(&name) {
+                    info!(seat = name, "Monitoring seat");
+                    state.add_seat(proxy.clone(), qhandle);
+                } else {
+                    info!(seat = name, "Ignoring seat because it is not configured");
+                }
+            }
+        }
     }
 }
-impl Dispatch<WlSeat, ()> for WlState {
+
+impl Dispatch<WlCallback, FinishedSeat> for WlState {
     fn event(
-        _state: &mut Self,
-        _proxy: &WlSeat,
-        _event: <WlSeat as wayland_client::Proxy>::Event,
-        _data: &(),
+        state: &mut Self,
+        _proxy: &WlCallback,
+        event: <WlCallback as wayland_client::Proxy>::Event,
+        data: &FinishedSeat,
         _conn: &wayland_client::Connection,
-        _qhandle: &wayland_client::QueueHandle<Self>,
+        qhandle: &wayland_client::QueueHandle<Self>,
     ) {
-        // we don't care about anything about the seat
+        let wayland_client::protocol::wl_callback::Event::Done { .. } = event else {
+            return;
+        };
+        let FinishedSeat(seat) = data;
+        let global_name = seat_data(seat).global_name;
+        // The device may already be back, like when the manager was replaced in the meantime.
+        let has_device = state
+            .shared_state
+            .data_control_devices
+            .lock()
+            .unwrap()
+            .contains_key(&global_name)
+            || state
+                .deferred_seats
+                .iter()
+                .any(|seat| seat_data(seat).global_name == global_name);
+        if !state.seat_globals.contains(&global_name) || has_device {
+            return;
+        }
+        // Finishing the new device too means the compositor doesn't let us watch this seat, so
+        // there is no point in staying alive. A supervisor can restart us.
+        if seat_data(seat).regrabbed.swap(true, Ordering::Relaxed) {
+            let _ = state.shared_state.exit_send.send(Err(eyre::eyre!(
+                "the compositor finished the data control device of a seat that is still there"
+            )));
+            return;
+        }
+        info!("Requesting a new data control device for a seat that is still there");
+        state.add_seat(seat.clone(), qhandle);
     }
 }
-impl Dispatch<ExtDataControlDeviceV1, ()> for WlState {
-    fn event(
-        state: &mut Self,
-        _proxy: &ExtDataControlDeviceV1,
-        event: <ExtDataControlDeviceV1 as wayland_client::Proxy>::Event,
-        _data: &(),
-        _conn: &wayland_client::Connection,
-        _qhandle: &wayland_client::QueueHandle<Self>,
-    ) {
-        match event {
-            ext_data_control_device_v1::Event::DataOffer { id: _ } => {
-                // A new offer is being prepared, we created the associated data in its creation and don't need to do anything
-            }
 
-            // The selection has been confirmed, we just properly got a new offer that we should use.
-            ext_data_control_device_v1::Event::Selection { id } => {
-                if let Some(offer) = id {
-                    let offer_data = offer
-                        .data::<InProgressOffer>()
-                        .expect("missing InProgressOffer data for ExtDataControlOfferV1");
+/// Reads the selection into the history on the capture pool.
+fn capture_offer(state: &WlState, offer: Box<dyn DataControlOffer>, seat: &WlSeat) {
+    let offer_data = offer.in_progress();
 
-                    let mime_types = offer_data.mime_types.lock().unwrap();
+    let mime_types = offer_data.mime_types.lock().unwrap();
 
-                    let has_password_manager_hint =
-                        mime_types.contains("x-kde-passwordManagerHint");
+    // Our own selection coming back, like after copying an entry from the history.
+    // The copy already updated the history, storing it again would only read the data for nothing.
+    if mime_types.contains(history::OWN_SOURCE_MIME) {
+        info!("Ignoring clipboard entry set by ourselves");
+        drop(mime_types);
+        offer.destroy();
+        return;
+    }
 
-                    let Some(mime) = MIME_TYPES.iter().find(|mime| mime_types.contains(**mime))
-                    else {
-                        warn!(
-                            "No supported mime type found. Found mime types: {:?}",
-                            mime_types
-                        );
-                        return;
-                    };
-                    drop(mime_types);
+    if mime_types.contains(TRANSIENT_MIME) {
+        info!("Ignoring transient clipboard entry");
+        drop(mime_types);
+        offer.destroy();
+        return;
+    }
 
-                    let history_state = state.shared_state.clone();
-                    let time = offer_data.time;
+    if let Some(ignored) = mime_types
+        .iter()
+        .find(|mime| history::is_ignored_mime(&state.shared_state.history.config, mime))
+    {
+        info!(
+            mime = %ignored,
+            "Ignoring clipboard entry because it offers an ignored mime type"
+        );
+        drop(mime_types);
+        offer.destroy();
+        return;
+    }
 
-                    let (reader, writer) = std::io::pipe().unwrap();
-                    offer.receive(mime.to_string(), writer.as_fd());
+    let has_password_manager_hint = mime_types.contains("x-kde-passwordManagerHint");
+    let source = history::source_from_mime_types(mime_types.iter().map(String::as_str));
 
-                    let password_manager_hint_reader = if has_password_manager_hint {
-                        let (reader, writer) = std::io::pipe().unwrap();
-                        offer.receive(mime.to_string(), writer.as_fd());
-                        Some(reader)
-                    } else {
-                        None
-                    };
+    let store_images = state.shared_state.history.config.store_images;
+    let Some(mime) = supported_mime(&mime_types, store_images) else {
+        if supported_mime(&mime_types, true).is_some() {
+            info!("Ignoring image because storing images is turned off");
+        } else {
+            warn!(?mime_types, "No supported mime type found");
+        }
+        drop(mime_types);
+        offer.destroy();
+        return;
+    };
+    let mime = mime.to_owned();
+    drop(mime_types);
+
+    let history_state = state.shared_state.clone();
+    let time = offer_data.time;
+    let seat_name = seat_data(seat).name.lock().unwrap().clone();
+    let timeout = Duration::from_secs(state.shared_state.history.config.capture_timeout_secs);
+
+    let (reader, writer) = std::io::pipe().unwrap();
+    offer.receive(mime.clone(), writer.as_fd());
+    // We read on a dedicated thread until the source is done, so don't let
+    // an inherited O_NONBLOCK turn a slow source into a failed read.
+    if let Err(err) = set_blocking(&reader) {
+        warn!("Failed to make clipboard pipe blocking: {err}");
+    }
 
-                    std::thread::spawn(move || {
-                        if let Some(mut password_manager_hint_reader) = password_manager_hint_reader
-                        {
-                            let mut buf = Vec::new();
-                            if password_manager_hint_reader.read_to_end(&mut buf).is_ok()
-                                && buf == b"secret"
-                            {
-                                info!("Clipboard entry is marked as secret, not storing it");
-                                return;
-                            }
-                        }
+    let password_manager_hint_reader = if has_password_manager_hint {
+        let (reader, writer) = std::io::pipe().unwrap();
+        offer.receive(mime.clone(), writer.as_fd());
+        Some(reader)
+    } else {
+        None
+    };
 
-                        let mime = mime.to_string();
-                        let result = read_fd_into_history(&history_state, time, mime, reader);
-                        if let Err(err) = result {
-                            warn!("Failed to read clipboard: {:?}", err)
-                        }
+    state.shared_state.capture_pool.execute(move || {
+        let is_secret = password_manager_hint_reader.is_some_and(|inner| {
+            let mut buf = Vec::new();
+            let mut reader = history::StallTimeout { inner, timeout };
+            reader.read_to_end(&mut buf).is_ok() && buf == b"secret"
+        });
 
-                        offer.destroy();
-                    });
-                }
-            }
-            // The offer has been confirmed to be a primary selection, do the necessary bookkeeping but we don't really care.
-            ext_data_control_device_v1::Event::PrimarySelection { id } => {
-                if let Some(id) = id {
-                    id.destroy();
-                }
-            }
-            ext_data_control_device_v1::Event::Finished => {
-                warn!("device finished :(");
+        if is_secret {
+            info!("Clipboard entry is marked as secret, not storing it");
+        } else {
+            let result = history::read_fd_into_history(
+                &history_state.history,
+                time,
+                mime,
+                seat_name,
+                source.map(str::to_owned),
+                history::StallTimeout {
+                    inner: reader,
+                    timeout,
+                },
+            );
+            if let Err(err) = result {
+                warn!("Failed to read clipboard: {:?}", err)
             }
-            _ => {}
         }
-    }
 
-    event_created_child!(WlState, ExtDataControlDeviceV1, [
-        EVT_DATA_OFFER_OPCODE => (ExtDataControlOfferV1, InProgressOffer {
-            mime_types: Default::default(),
-            time: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap(),
-        }),
-    ]);
+        offer.destroy();
+
+        if let Some(capture_done) = &history_state.capture_done {
+            let _ = capture_done.send(());
+        }
+    });
 }
 
-impl Dispatch<ExtDataControlOfferV1, InProgressOffer> for WlState {
-    fn event(
-        _state: &mut Self,
-        _proxy: &ExtDataControlOfferV1,
-        event: <ExtDataControlOfferV1 as wayland_client::Proxy>::Event,
-        data: &InProgressOffer,
-        _conn: &wayland_client::Connection,
-        _qhandle: &wayland_client::QueueHandle<Self>,
-    ) {
-        match event {
-            ext_data_control_offer_v1::Event::Offer { mime_type } => {
-                data.mime_types.lock().unwrap().insert(mime_type);
+impl WlState {
+    /// Sends our selection to a pasting client on the send pool.
+    fn send_selection(&self, data: &OfferData, mime_type: String, fd: std::os::fd::OwnedFd) {
+        let offer_data = data.clone();
+
+        self.shared_state.send_pool.execute(move || {
+            let Some(data) = offer_data.data_as(&mime_type) else {
+                // Closing the pipe without writing anything tells the requester that it failed.
+                return;
+            };
+            let mut writer = BufWriter::new(PipeWriter::from(fd));
+
+            let result = writer.write_all(&data);
+            if let Err(err) = result {
+                warn!("Failed to write to requester: {:?}", err);
             }
-            _ => {}
-        }
+            let result = writer.into_inner();
+            if let Err(err) = result {
+                warn!("Failed to write to requester: {:?}", err);
+            }
+        });
     }
 }
 
-impl Dispatch<ExtDataControlSourceV1, OfferData> for WlState {
-    fn event(
-        _state: &mut Self,
-        proxy: &ExtDataControlSourceV1,
-        event: <ExtDataControlSourceV1 as Proxy>::Event,
-        data: &OfferData,
-        _conn: &wayland_client::Connection,
-        _qhandle: &QueueHandle<Self>,
-    ) {
-        match event {
-            ext_data_control_source_v1::Event::Send { mime_type: _, fd } => {
-                let data = data.0.clone();
-
-                std::thread::spawn(move || {
-                    let mut writer = BufWriter::new(PipeWriter::from(fd));
+impl OfferData {
+    /// Returns the data for a paste of `mime_type`, transcoding images if it's a different format.
+    fn data_as(&self, mime_type: &str) -> Option<Arc<[u8]>> {
+        if !history::TRANSCODABLE_IMAGE_MIMES.contains(&mime_type) {
+            return Some(self.data.clone());
+        }
+        if let Some(transcoded) = self.transcoded.lock().unwrap().get(mime_type) {
+            return Some(transcoded.clone());
+        }
 
-                    let result = writer.write_all(&data);
-                    if let Err(err) = result {
-                        warn!("Failed to write to requester: {:?}", err);
-                    }
-                    let result = writer.into_inner();
-                    if let Err(err) = result {
-                        warn!("Failed to write to requester: {:?}", err);
-                    }
-                });
+        // Not holding the lock while transcoding, pastes of other formats don't need to wait.
+        match history::transcode_image(&self.data, &self.mime, mime_type) {
+            Ok(None) => Some(self.data.clone()),
+            Ok(Some(transcoded)) => {
+                info!(
+                    from = self.mime,
+                    to = mime_type,
+                    "Transcoded image for pasting"
+                );
+                let transcoded: Arc<[u8]> = transcoded.into();
+                self.transcoded
+                    .lock()
+                    .unwrap()
+                    .insert(mime_type.to_owned(), transcoded.clone());
+                Some(transcoded)
             }
-            ext_data_control_source_v1::Event::Cancelled => {
-                proxy.destroy();
+            Err(err) => {
+                warn!(
+                    from = self.mime,
+                    to = mime_type,
+                    "Failed to transcode image: {err:#}"
+                );
+                None
             }
-            _ => {}
         }
     }
 }
 
+/// Returns the offered mime type to receive, preferring the order of [`MIME_TYPES`].
+/// Text with a UTF-8 charset counts as `text/plain`, it's normalized when it's stored.
+fn supported_mime(mime_types: &HashSet<String>, store_images: bool) -> Option<&str> {
+    MIME_TYPES
+        .iter()
+        .filter(|mime| store_images || !mime.starts_with("image/"))
+        .find_map(|&supported| {
+            let offered = mime_types.get(supported).or_else(|| {
+                (supported == "text/plain")
+                    .then(|| {
+                        mime_types
+                            .iter()
+                            .find(|mime| history::is_utf8_text_mime(mime))
+                    })
+                    .flatten()
+            })?;
+            Some(offered.as_str())
+        })
+}
+
 impl SharedState {
+    /// Wakes up the Wayland thread so that it flushes the requests we just made.
+    /// It only waits for the pipe to become readable, so one unread byte is enough to wake it up.
     fn notify_wayland_request(&self) {
-        let _ = (&self.notify_write_send).write_all(&[0]);
+        match (&self.notify_write_send).write(&[0]) {
+            Ok(_) => {}
+            // The pipe is full of unread bytes, the thread is going to wake up anyways.
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+            Err(err) if err.kind() == ErrorKind::BrokenPipe => {
+                let _ = self
+                    .exit_send
+                    .send(Err(eyre::eyre!("the Wayland thread has exited")));
+            }
+            Err(err) => warn!("Failed to wake up the Wayland thread: {err}"),
+        }
+    }
+}
+
+#[cfg(feature = "dbus")]
+impl clippyboard_daemon::dbus::State for SharedState {
+    fn history(&self) -> &History {
+        &self.history
+    }
+}
+
+impl Clipboard for SharedState {
+    fn set_selection(&self, mime: &str, data: Arc<[u8]>, transient: bool) -> eyre::Result<()> {
+        do_copy_into_clipboard(mime, data, transient, false, self)?;
+
+        self.notify_wayland_request();
+
+        Ok(())
+    }
+
+    fn set_primary_selection(&self, mime: &str, data: Arc<[u8]>) -> eyre::Result<()> {
+        // We don't store the primary selection, so it doesn't need to be marked as transient.
+        do_copy_into_clipboard(mime, data, false, true, self)?;
+
+        self.notify_wayland_request();
+
+        Ok(())
+    }
+
+    fn clear_selection(&self) -> eyre::Result<()> {
+        let devices = self.data_control_devices.lock().unwrap();
+        for device in selection_devices(&devices) {
+            device.set_selection(None, false, &self.qh)?;
+        }
+
+        self.notify_wayland_request();
+
+        Ok(())
     }
 }
 
 fn do_copy_into_clipboard(
-    entry: HistoryItem,
+    mime: &str,
+    data: Arc<[u8]>,
+    transient: bool,
+    primary: bool,
     shared_state: &SharedState,
 ) -> Result<(), eyre::Error> {
-    for device in &*shared_state.data_control_devices.lock().unwrap() {
-        let data_source = shared_state
-            .data_control_manager
-            .get()
-            .expect("data manger not found")
-            .create_data_source(&shared_state.qh, OfferData(entry.data.clone()));
-
-        if entry.mime == "text/plain" {
-            // Just like wl_clipboard_rs, we also offer some extra mimes for text.
-            let text_mimes = [
-                "text/plain;charset=utf-8",
-                "text/plain",
-                "STRING",
-                "UTF8_STRING",
-                "TEXT",
-            ];
-            for mime in text_mimes {
-                data_source.offer(mime.to_string());
-            }
-        } else {
-            data_source.offer(entry.mime.clone());
-        }
+    if shared_state.data_control_manager.lock().unwrap().is_none() {
+        bail!("no data control manager is currently available");
+    }
 
-        device.1.set_selection(Some(&data_source));
+    let mut mime_types = if mime == "text/plain" {
+        // Just like wl_clipboard_rs, we also offer some extra mimes for text.
+        [
+            "text/plain;charset=utf-8",
+            "text/plain",
+            "STRING",
+            "UTF8_STRING",
+            "TEXT",
+        ]
+        .map(str::to_owned)
+        .to_vec()
+    } else if history::TRANSCODABLE_IMAGE_MIMES.contains(&mime) {
+        // The stored format first, so that it's preferred by apps that accept several.
+        let mut mime_types = vec![mime.to_owned()];
+        mime_types.extend(
+            history::TRANSCODABLE_IMAGE_MIMES
+                .iter()
+                .filter(|&&other| other != mime)
+                .map(|&other| other.to_owned()),
+        );
+        mime_types
+    } else {
+        vec![mime.to_owned()]
+    };
+    mime_types.push(history::OWN_SOURCE_MIME.to_owned());
+    if transient {
+        mime_types.push(TRANSIENT_MIME.to_owned());
+    }
+
+    // Without a device, like before the compositor advertised a seat, the copy would vanish.
+    let devices = shared_state.data_control_devices.lock().unwrap();
+    if devices.is_empty() {
+        bail!("no seat available to copy to");
+    }
+    let offer_data = OfferData {
+        mime: mime.to_owned(),
+        data,
+        transcoded: Default::default(),
+    };
+    for device in selection_devices(&devices) {
+        device.set_selection(
+            Some((&mime_types, offer_data.clone())),
+            primary,
+            &shared_state.qh,
+        )?;
     }
 
     Ok(())
 }
 
+/// The devices to set a selection on, see [`seats::selection_targets`].
+fn selection_devices(
+    devices: &HashMap<u32, Box<dyn DataControlDevice>>,
+) -> impl Iterator<Item = &dyn DataControlDevice> {
+    let names = devices
+        .iter()
+        .map(|(&global_name, device)| {
+            (
+                global_name,
+                device.generation(),
+                seat_data(device.seat()).name.lock().unwrap().clone(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let targets =
+        seats::selection_targets(names.iter().map(|(global_name, generation, seat_name)| {
+            (*global_name, *generation, seat_name.as_deref())
+        }));
+    targets
+        .into_iter()
+        .map(|global_name| &*devices[&global_name])
+}
+
+fn set_blocking(fd: impl AsFd) -> rustix::io::Result<()> {
+    let flags = rustix::fs::fcntl_getfl(&fd)?;
+    rustix::fs::fcntl_setfl(&fd, flags - OFlags::NONBLOCK)
+}
+
 fn dispatch_wayland(
     mut queue: EventQueue<WlState>,
     mut wl_state: WlState,
@@ -350,6 +881,7 @@ fn dispatch_wayland(
         queue
             .dispatch_pending(&mut wl_state)
             .wrap_err("dispatching Wayland events")?;
+        wl_state.capture_pending_if_due();
 
         let read_guard = queue
             .prepare_read()
@@ -359,7 +891,18 @@ fn dispatch_wayland(
         let pollfd1_read = PollFd::from_borrowed_fd(read_guard.connection_fd(), PollFlags::IN);
         let pollfd_signal = PollFd::from_borrowed_fd(notify_write_recv.as_fd(), PollFlags::IN);
 
-        let _ = rustix::event::poll(&mut [pollfd1_read, pollfd_signal], None);
+        // Wake up in time to capture the end of a burst.
+        let timeout = wl_state.pending_capture.as_ref().map(|pending| {
+            Timespec::try_from(pending.deadline.saturating_duration_since(Instant::now()))
+                .unwrap_or(Timespec {
+                    tv_sec: 0,
+                    tv_nsec: 0,
+                })
+        });
+        let _ = rustix::event::poll(&mut [pollfd1_read, pollfd_signal], timeout.as_ref());
+
+        // Drain the wakeup bytes, otherwise the pipe stays readable and we never sleep again.
+        while let Ok(1..) = (&notify_write_recv).read(&mut [0; 64]) {}
 
         read_guard
             .read_without_dispatch()
@@ -367,163 +910,128 @@ fn dispatch_wayland(
     }
 }
 
-#[tracing::instrument(skip(peer, shared_state))]
-fn handle_peer(mut peer: UnixStream, shared_state: &SharedState) -> eyre::Result<()> {
-    let mut request = [0; 1];
-    let Ok(()) = peer.read_exact(&mut request) else {
-        return Ok(());
-    };
-    match request[0] {
-        clippyboard_shared::MESSAGE_READ => {
-            let items = shared_state.items.lock().unwrap();
-
-            ciborium::into_writer(items.as_slice(), BufWriter::new(peer))
-                .wrap_err("writing items to socket")?;
-        }
-        clippyboard_shared::MESSAGE_COPY => {
-            handle_copy_message(peer, shared_state).wrap_err("handling copy message")?;
-        }
-        clippyboard_shared::MESSAGE_CLEAR => {
-            handle_clear_message(&shared_state)?;
-            info!("Cleared history and clipboard");
-        }
-        _ => {}
-    };
-    Ok(())
-}
-
-struct OfferData(Arc<[u8]>);
-
-fn handle_copy_message(
-    mut peer: UnixStream,
-    shared_state: &SharedState,
-) -> Result<(), eyre::Error> {
-    let mut id = [0; 8];
-    peer.read_exact(&mut id).wrap_err("failed to read id")?;
-    let id = u64::from_le_bytes(id);
-    let mut items = shared_state.items.lock().unwrap();
-    let Some(idx) = items.iter().position(|item| item.id == id) else {
-        return Ok(());
-    };
-    let item = items.remove(idx);
-    items.push(item.clone());
-
-    drop(items);
-
-    do_copy_into_clipboard(item, &shared_state).wrap_err("doing copy")?;
-
-    shared_state.notify_wayland_request();
-
-    Ok(())
+/// User data of our sources, shared by the sources of all seats.
+#[derive(Clone)]
+struct OfferData {
+    mime: String,
+    data: Arc<[u8]>,
+    /// The data in other image formats, by MIME type, once it was pasted as them.
+    transcoded: Arc<Mutex<HashMap<String, Arc<[u8]>>>>,
 }
 
-fn handle_clear_message(shared_state: &SharedState) -> eyre::Result<()> {
-    shared_state.items.lock().unwrap().clear();
-
-    for device in &*shared_state.data_control_devices.lock().unwrap() {
-        device.1.set_selection(None);
-    }
-
-    shared_state.notify_wayland_request();
-
-    Ok(())
+fn main() -> std::process::ExitCode {
+    clippyboard_shared::exit::report(run())
 }
 
-fn read_fd_into_history(
-    history_state: &SharedState,
-    time: std::time::Duration,
-    mime: String,
-    data_reader: impl Read,
-) -> Result<(), eyre::Error> {
-    let mut data_reader = BufReader::new(data_reader).take(MAX_ENTRY_SIZE);
-    let mut data = Vec::new();
-    data_reader
-        .read_to_end(&mut data)
-        .wrap_err("reading content data")?;
-
-    let new_entry = HistoryItem {
-        id: history_state
-            .next_item_id
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed),
-        mime: mime.to_string(),
-        data: data.into(),
-        created_time: u64::try_from(time.as_millis()).unwrap(),
-    };
-    let mut items = history_state.items.lock().unwrap();
-    if items
-        .last()
-        .is_some_and(|last| last.mime == new_entry.mime && last.data == new_entry.data)
-    {
-        info!("INFO: Skipping store of new item because it is identical to last one");
-        return Ok(());
+fn run() -> eyre::Result<()> {
+    let args = parse_args()?;
+    if args.doctor {
+        return doctor::run();
     }
-
-    items.push(new_entry);
-    let mut running_total = 0;
-    let mut cutoff = None;
-    for (idx, item) in items.iter().enumerate().rev() {
-        running_total += item.data.len() + std::mem::size_of::<HistoryItem>();
-        if running_total > crate::MAX_HISTORY_BYTE_SIZE {
-            cutoff = Some(idx);
-        }
-    }
-    if let Some(cutoff) = cutoff {
-        info!(
-            "Dropping old {} items because limit of {} bytes was reached for the history",
-            cutoff + 1,
-            crate::MAX_HISTORY_BYTE_SIZE
+    if rustix::process::geteuid().is_root() && !args.allow_root {
+        bail!(
+            "refusing to run as root. the daemon stores the clipboard of the user running it, \
+            so run it as that user, or pass --allow-root if you really mean to"
         );
-        items.splice(0..=cutoff, []);
     }
-    info!(
-        "Successfully stored clipboard value of mime type {mime} (new history size {running_total})"
-    );
-    Ok(())
-}
+    let socket_path = clippyboard_shared::socket_path();
 
-fn main() -> eyre::Result<()> {
-    let socket_path = clippyboard_shared::socket_path()?;
+    // SAFETY: no other threads were spawned yet.
+    let activated_listener = unsafe { systemd::activated_listener() }
+        .wrap_err("getting the socket passed by systemd")?;
+    // A socket passed by systemd belongs to systemd, so we must never remove it.
+    let owns_socket = activated_listener.is_none();
 
-    let socket_path2 = socket_path.clone();
+    let socket_path2 = socket_path.path.clone();
     let _ = ctrlc::set_handler(move || {
-        cleanup(&socket_path2);
+        if owns_socket {
+            cleanup(&socket_path2);
+        }
         std::process::exit(130); // sigint
     });
 
-    let Err(err) = main_inner(&socket_path);
+    let result = main_inner(&socket_path, activated_listener, args);
 
-    if let Some(ioerr) = err.downcast_ref::<io::Error>()
-        && ioerr.kind() == ErrorKind::AddrInUse
-    {
-        // no cleanup
-        return Err(err);
+    let addr_in_use = result.as_ref().is_err_and(|err| {
+        err.downcast_ref::<io::Error>()
+            .is_some_and(|ioerr| ioerr.kind() == ErrorKind::AddrInUse)
+    });
+    // Another daemon is using the socket, so we must not remove it.
+    if owns_socket && !addr_in_use {
+        cleanup(&socket_path.path);
     }
-
-    cleanup(&socket_path);
-    Err(err)
+    if addr_in_use {
+        return result.wrap_err(Failure::SocketInUse);
+    }
+    result
 }
 
-pub fn main_inner(socket_path: &PathBuf) -> eyre::Result<Infallible> {
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or(EnvFilter::new("info")))
-        .init();
+/// Runs the daemon until the Wayland connection is lost or `--oneshot` is done.
+/// Cleanup of the socket is left to the caller.
+pub fn main_inner(
+    socket_path: &SocketPath,
+    activated_listener: Option<UnixListener>,
+    args: Args,
+) -> eyre::Result<()> {
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or(EnvFilter::new("info")));
+    match args.log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+    let oneshot = args.oneshot;
+
+    let mut config = Config::load().wrap_err("loading config")?;
+    match std::env::var("CLIPPYBOARD_STORE_IMAGES").as_deref() {
+        Ok("0") => config.daemon.store_images = false,
+        Ok("1") => config.daemon.store_images = true,
+        Ok(value) => bail!("CLIPPYBOARD_STORE_IMAGES must be 0 or 1, got {value}"),
+        Err(_) => {}
+    }
 
-    let socket = UnixListener::bind(&socket_path)
-        .wrap_err_with(|| format!("binding path {}", socket_path.display()))?;
+    let socket = match activated_listener {
+        Some(socket) => {
+            info!("Using the socket passed by systemd");
+            socket
+        }
+        None => {
+            if socket_path.is_fallback {
+                warn!(
+                    "XDG_RUNTIME_DIR is not set, falling back to {}",
+                    socket_path.path.display()
+                );
+            }
+            bind_socket(&socket_path.path)
+                .wrap_err_with(|| format!("binding path {}", socket_path.path.display()))?
+        }
+    };
 
-    let conn =
-        wayland_client::Connection::connect_to_env().wrap_err("connecting to the compositor")?;
+    let conn = connect_to_compositor()?;
 
     let mut queue = conn.new_event_queue::<WlState>();
 
     let (notify_write_recv, notify_write_send) = std::io::pipe().expect("todo");
 
+    // Everything that should stop the daemon is sent here, so that exiting happens in one place.
+    let (exit_send, exit_recv) = mpsc::channel::<eyre::Result<()>>();
+
+    let (capture_done, capture_done_recv) = match oneshot {
+        Some(_) => {
+            let (send, recv) = mpsc::channel();
+            (Some(send), Some(recv))
+        }
+        None => (None, None),
+    };
+
     let shared_state = Arc::new(SharedState {
-        next_item_id: AtomicU64::new(0),
-        items: Mutex::new(Vec::<HistoryItem>::new()),
+        history: History::new(config.daemon),
+        capture_done,
         notify_write_send,
+        exit_send: exit_send.clone(),
+        capture_pool: WorkerPool::new("capture", WORKER_THREADS),
+        send_pool: WorkerPool::new("send", WORKER_THREADS),
 
-        data_control_manager: OnceLock::new(),
+        data_control_manager: Mutex::new(None),
         data_control_devices: Mutex::new(HashMap::new()),
         qh: queue.handle(),
     });
@@ -532,6 +1040,12 @@ pub fn main_inner(socket_path: &PathBuf) -> eyre::Result<Infallible> {
 
     let mut wl_state = WlState {
         deferred_seats: Vec::new(),
+        data_control_manager_name: None,
+        wlr_data_control_global: None,
+        seat_globals: HashSet::new(),
+        recent_selections: VecDeque::new(),
+        pending_capture: None,
+        next_device_generation: 0,
 
         shared_state: history_state2,
     };
@@ -542,53 +1056,251 @@ pub fn main_inner(socket_path: &PathBuf) -> eyre::Result<Infallible> {
         .roundtrip(&mut wl_state)
         .wrap_err("failed to set up wayland state")?;
 
-    if wl_state.shared_state.data_control_manager.get().is_none() {
-        bail!(
-            "{} not found, the ext-data-control-v1 Wayland extension is likely unsupported by your compositor.\n\
+    if wl_state
+        .shared_state
+        .data_control_manager
+        .lock()
+        .unwrap()
+        .is_none()
+    {
+        return Err(eyre::eyre!(
+            "neither {} nor {} found, the ext-data-control-v1 and wlr-data-control-unstable-v1 Wayland extensions \
+            are likely unsupported by your compositor.\n\
             check https://wayland.app/protocols/ext-data-control-v1#compositor-support\
             ",
-            ExtDataControlManagerV1::interface().name
-        );
+            ExtDataControlManagerV1::interface().name,
+            ZwlrDataControlManagerV1::interface().name,
+        ))
+        .wrap_err(Failure::ProtocolUnsupported);
     }
 
+    // The devices were only created while handling the first roundtrip. Upon creation, they get the
+    // current selection, so wait for that to make sure we start reading it before handling requests.
+    queue
+        .roundtrip(&mut wl_state)
+        .wrap_err("failed to get the current selection")?;
+
     rustix::fs::fcntl_setfl(notify_write_recv.as_fd(), OFlags::NONBLOCK).expect("todo");
+    rustix::fs::fcntl_setfl(shared_state.notify_write_send.as_fd(), OFlags::NONBLOCK)
+        .expect("todo");
     rustix::fs::fcntl_setfl(conn.as_fd(), OFlags::NONBLOCK).expect("TODO");
 
-    let socket_path_clone = socket_path.to_owned();
+    let exit_send2 = exit_send.clone();
     std::thread::spawn(move || {
-        if let Err(err) = dispatch_wayland(queue, wl_state, notify_write_recv) {
-            error!("error on Wayland thread: {err:?}");
-            cleanup(&socket_path_clone);
-            std::process::exit(1);
+        let result = dispatch_wayland(queue, wl_state, notify_write_recv);
+        let _ = exit_send2.send(result.wrap_err("lost the Wayland connection"));
+    });
+
+    #[cfg(feature = "dbus")]
+    if let Err(err) = clippyboard_daemon::dbus::serve(shared_state.clone()) {
+        warn!("Failed to set up the D-Bus interface: {err:?}");
+    }
+
+    if let Some(port) = shared_state.history.config.http_port {
+        #[cfg(feature = "http")]
+        if let Err(err) =
+            clippyboard_daemon::http::serve(port, shared_state.clone(), |state| &state.history)
+        {
+            warn!("Failed to set up the HTTP endpoint: {err:?}");
+        }
+        #[cfg(not(feature = "http"))]
+        warn!(
+            port,
+            "http_port is configured, but the daemon was built without the http feature"
+        );
+    }
+
+    if let Some(command) = shared_state.history.config.capture_hook.clone() {
+        let new_items = shared_state.history.subscribe();
+        let shared_state = shared_state.clone();
+        std::thread::spawn(move || {
+            for id in new_items {
+                run_capture_hook(&shared_state.history, &command, id);
+            }
+        });
+    }
+
+    if shared_state.history.config.max_age_secs.is_some() {
+        let shared_state = shared_state.clone();
+        std::thread::spawn(move || {
+            loop {
+                std::thread::sleep(EXPIRY_SWEEP_INTERVAL);
+                let now = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap();
+                let now = u64::try_from(now.as_millis()).unwrap();
+                history::remove_expired_items(&shared_state.history, now);
+            }
+        });
+    }
+
+    if let Some(oneshot) = oneshot {
+        let shared_state = shared_state.clone();
+        let capture_done_recv = capture_done_recv.unwrap();
+        let exit_send = exit_send.clone();
+        std::thread::spawn(move || {
+            run_oneshot(oneshot, capture_done_recv, &shared_state.history);
+            let _ = exit_send.send(Ok(()));
+        });
+    }
+
+    info!("Listening on {}", socket_path.path.display());
+    if let Err(err) = systemd::notify_ready() {
+        warn!("Failed to notify systemd about readiness: {err:?}");
+    }
+
+    std::thread::spawn(move || {
+        for peer in socket.incoming() {
+            match peer {
+                Ok(peer) => {
+                    let history_state = shared_state.clone();
+                    std::thread::spawn(move || {
+                        let result =
+                            peer::handle_peer(peer, &history_state.history, &*history_state);
+                        if let Err(err) = result {
+                            warn!("Error handling peer: {err:?}");
+                        }
+                    });
+                }
+                Err(err) => {
+                    warn!("Error accepting peer: {err}");
+                }
+            }
         }
     });
 
-    info!("Listening on {}", socket_path.display());
+    exit_recv
+        .recv()
+        .expect("the Wayland thread only exits after sending")
+}
 
-    for peer in socket.incoming() {
-        match peer {
-            Ok(peer) => {
-                let history_state = shared_state.clone();
-                std::thread::spawn(move || {
-                    let result = handle_peer(peer, &history_state);
-                    if let Err(err) = result {
-                        warn!("Error handling peer: {err:?}");
-                    }
-                });
+fn run_capture_hook(history: &History, command: &str, id: u64) {
+    let Some((mime, size)) = history
+        .items
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|item| item.id == id)
+        .map(|item| (item.mime.clone(), item.data.len()))
+    else {
+        return;
+    };
+
+    let child = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("CLIPPYBOARD_ID", id.to_string())
+        .env("CLIPPYBOARD_MIME", mime)
+        .env("CLIPPYBOARD_SIZE", size.to_string())
+        .stdin(std::process::Stdio::null())
+        .spawn();
+    match child {
+        // Don't wait for slow hooks before running the next one, but still reap them.
+        Ok(mut child) => {
+            std::thread::spawn(move || match child.wait() {
+                Ok(status) if !status.success() => warn!("Capture hook failed with {status}"),
+                Ok(_) => {}
+                Err(err) => warn!("Failed to wait for capture hook: {err}"),
+            });
+        }
+        Err(err) => warn!("Failed to run capture hook: {err}"),
+    }
+}
+
+/// The compositor may not be ready yet when the daemon is started at login,
+/// so connecting is retried for `CLIPPYBOARD_CONNECT_TIMEOUT_SECS`, 10 by default.
+fn connect_to_compositor() -> eyre::Result<wayland_client::Connection> {
+    let timeout = match std::env::var("CLIPPYBOARD_CONNECT_TIMEOUT_SECS") {
+        Ok(secs) => Duration::from_secs(
+            secs.parse()
+                .wrap_err_with(|| format!("invalid CLIPPYBOARD_CONNECT_TIMEOUT_SECS {secs}"))?,
+        ),
+        Err(_) => Duration::from_secs(10),
+    };
+    let start = Instant::now();
+    let mut backoff = Duration::from_millis(100);
+    loop {
+        match wayland_client::Connection::connect_to_env() {
+            Ok(conn) => return Ok(conn),
+            // Only a missing compositor can go away by waiting.
+            Err(wayland_client::ConnectError::NoCompositor)
+                if start.elapsed() + backoff < timeout =>
+            {
+                info!("The compositor is not available yet, retrying in {backoff:?}");
+                std::thread::sleep(backoff);
+                backoff = (backoff * 2).min(Duration::from_secs(2));
             }
-            Err(err) => {
-                warn!("Error accepting peer: {err}");
+            Err(err) => return Err(err).wrap_err(Failure::WaylandUnavailable),
+        }
+    }
+}
+
+/// Binds the socket, replacing a stale one left by a crashed daemon, only accessible by us since
+/// everyone who can connect can read the history.
+fn bind_socket(socket_path: &Path) -> io::Result<UnixListener> {
+    // Setting the permissions after binding alone would leave a moment where others can connect.
+    let old_umask = rustix::process::umask(rustix::fs::Mode::from_raw_mode(0o177));
+    let result = bind_socket_inner(socket_path);
+    rustix::process::umask(old_umask);
+
+    let listener = result?;
+    std::fs::set_permissions(socket_path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+fn bind_socket_inner(socket_path: &Path) -> io::Result<UnixListener> {
+    match UnixListener::bind(socket_path) {
+        Err(err) if err.kind() == ErrorKind::AddrInUse => {
+            // If nobody is listening anymore, connecting is refused.
+            match UnixStream::connect(socket_path) {
+                Err(connect_err) if connect_err.kind() == ErrorKind::ConnectionRefused => {
+                    warn!("Removing stale socket at {}", socket_path.display());
+                    std::fs::remove_file(socket_path)?;
+                    UnixListener::bind(socket_path)
+                }
+                // Another daemon is running, the caller must not remove its socket.
+                _ => Err(err),
             }
         }
+        result => result,
     }
+}
+
+/// Waits until the oneshot limits are reached and prints the history.
+fn run_oneshot(oneshot: Oneshot, capture_done: mpsc::Receiver<()>, history: &History) {
+    let deadline = oneshot.timeout.map(|timeout| Instant::now() + timeout);
+    let mut events = 0;
 
-    unreachable!("socket.incoming will never return None")
+    while oneshot
+        .max_events
+        .is_none_or(|max_events| events < max_events)
+    {
+        let result = match deadline {
+            Some(deadline) => {
+                capture_done.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            }
+            None => capture_done
+                .recv()
+                .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+        match result {
+            Ok(()) => events += 1,
+            Err(_) => break,
+        }
+    }
+
+    info!("Exiting after {events} clipboard events because of --oneshot");
+    let items = history.items.lock().unwrap();
+    if let Err(err) = serde_json::to_writer(io::stdout().lock(), &*items) {
+        error!("Failed to print history: {err}");
+    }
+    println!();
 }
 
 fn cleanup(socket_path: &PathBuf) {
     static HAS_DONE_CLEANUP: AtomicBool = AtomicBool::new(false);
 
     if !HAS_DONE_CLEANUP.swap(true, Ordering::Relaxed) {
-        let _ = std::fs::remove_file(&socket_path);
+        let _ = std::fs::remove_file(socket_path);
     }
 }
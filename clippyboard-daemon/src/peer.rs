@@ -0,0 +1,510 @@
+use crate::history::{self, History, MAX_ENTRY_SIZE};
+use clippyboard_shared::{HistoryItem, HistoryItemMeta};
+use eyre::{Context, bail};
+use std::io::{BufWriter, ErrorKind, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+use tracing::{info, warn};
+
+const MAX_MIME_SIZE: u64 = 1000;
+const MAX_TAG_SIZE: u64 = 1000;
+
+/// The system clipboard, which is the Wayland connection outside of tests.
+pub trait Clipboard {
+    /// `transient` content was not copied by the user and must not be stored when it comes back to us.
+    fn set_selection(&self, mime: &str, data: Arc<[u8]>, transient: bool) -> eyre::Result<()>;
+    fn set_primary_selection(&self, mime: &str, data: Arc<[u8]>) -> eyre::Result<()>;
+    fn clear_selection(&self) -> eyre::Result<()>;
+}
+
+#[tracing::instrument(skip(peer, history, clipboard))]
+pub fn handle_peer(
+    mut peer: UnixStream,
+    history: &History,
+    clipboard: &impl Clipboard,
+) -> eyre::Result<()> {
+    let mut version = [0; 1];
+    let Ok(()) = peer.read_exact(&mut version) else {
+        return Ok(());
+    };
+    peer.write_all(&[clippyboard_shared::PROTOCOL_VERSION])
+        .wrap_err("writing protocol version")?;
+    if version[0] != clippyboard_shared::PROTOCOL_VERSION {
+        warn!(
+            client_version = version[0],
+            our_version = clippyboard_shared::PROTOCOL_VERSION,
+            "Client speaks a different protocol version, closing connection"
+        );
+        return Ok(());
+    }
+
+    // Clients can send any number of messages, until they close the connection.
+    loop {
+        let mut request = [0; 1];
+        let Ok(()) = peer.read_exact(&mut request) else {
+            return Ok(());
+        };
+        match request[0] {
+            clippyboard_shared::MESSAGE_READ => {
+                let items = history.items.lock().unwrap();
+
+                let mut writer = BufWriter::new(&mut peer);
+                ciborium::into_writer(&*items, &mut writer).wrap_err("writing items to socket")?;
+                writer.flush().wrap_err("writing items to socket")?;
+            }
+            clippyboard_shared::MESSAGE_READ_META => {
+                handle_read_meta_message(&mut peer, history)
+                    .wrap_err("handling read meta message")?;
+            }
+            clippyboard_shared::MESSAGE_GET => {
+                handle_get_message(&mut peer, history).wrap_err("handling get message")?;
+            }
+            clippyboard_shared::MESSAGE_TAG | clippyboard_shared::MESSAGE_UNTAG => {
+                handle_tag_message(
+                    &mut peer,
+                    history,
+                    request[0] == clippyboard_shared::MESSAGE_TAG,
+                )
+                .wrap_err("handling tag message")?;
+            }
+            clippyboard_shared::MESSAGE_SUBSCRIBE => {
+                return stream_new_items(&mut peer, history);
+            }
+            clippyboard_shared::MESSAGE_PING => {
+                peer.write_all(&[clippyboard_shared::MESSAGE_PING])
+                    .wrap_err("answering ping")?;
+            }
+            clippyboard_shared::MESSAGE_COPY => {
+                handle_copy_message(&mut peer, history, clipboard)
+                    .wrap_err("handling copy message")?;
+            }
+            clippyboard_shared::MESSAGE_COPY_PRIMARY => {
+                handle_copy_primary_message(&mut peer, history, clipboard)
+                    .wrap_err("handling copy primary message")?;
+            }
+            clippyboard_shared::MESSAGE_CLEAR => {
+                handle_clear_message(&mut peer, history, clipboard)?;
+            }
+            clippyboard_shared::MESSAGE_UNDO_CLEAR => {
+                handle_undo_clear_message(&mut peer, history, clipboard)
+                    .wrap_err("handling undo clear message")?;
+            }
+            clippyboard_shared::MESSAGE_STORE_RAW => {
+                handle_store_raw_message(&mut peer, history)
+                    .wrap_err("handling store raw message")?;
+            }
+            clippyboard_shared::MESSAGE_SET_SELECTION => {
+                handle_set_selection_message(&mut peer, clipboard)
+                    .wrap_err("handling set selection message")?;
+            }
+            clippyboard_shared::MESSAGE_DELETE_RANGE => {
+                handle_delete_range_message(&mut peer, history)
+                    .wrap_err("handling delete range message")?;
+            }
+            clippyboard_shared::MESSAGE_COPY_AS_FILE => {
+                handle_copy_as_file_message(&mut peer, history, clipboard)
+                    .wrap_err("handling copy as file message")?;
+            }
+            message => {
+                // We don't know where the next message starts.
+                warn!(message, "Unknown message, closing connection");
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn handle_read_meta_message(peer: &mut UnixStream, history: &History) -> eyre::Result<()> {
+    let mut preview_chars = [0; 8];
+    peer.read_exact(&mut preview_chars)
+        .wrap_err("failed to read preview length")?;
+    let preview_chars = usize::try_from(u64::from_le_bytes(preview_chars)).unwrap_or(usize::MAX);
+
+    // Cloning only clones the data `Arc`s, so that thumbnails are created without holding the lock.
+    let items = history.items.lock().unwrap().clone();
+    let ids = items.iter().map(|item| item.id).collect::<Vec<_>>();
+    history::prune_thumbnail_cache(history, &ids);
+    let items = items
+        .iter()
+        .map(|item| HistoryItemMeta {
+            thumbnail: history::list_thumbnail(history, item),
+            ..HistoryItemMeta::new(item, preview_chars)
+        })
+        .collect::<Vec<_>>();
+
+    let mut writer = BufWriter::new(peer);
+    ciborium::into_writer(&items, &mut writer).wrap_err("writing items to socket")?;
+    writer.flush().wrap_err("writing items to socket")?;
+    Ok(())
+}
+
+fn handle_get_message(peer: &mut UnixStream, history: &History) -> eyre::Result<()> {
+    let mut id = [0; 8];
+    peer.read_exact(&mut id).wrap_err("failed to read id")?;
+    let id = u64::from_le_bytes(id);
+
+    // Only clone the Arc so the lock isn't held while writing.
+    let data = history
+        .items
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|item| item.id == id)
+        .map(|item| item.data.clone());
+
+    let mut writer = BufWriter::new(peer);
+    match data {
+        Some(data) => {
+            writer.write_all(&[1]).wrap_err("writing response")?;
+            clippyboard_shared::write_framed(&mut writer, &data).wrap_err("writing data")?;
+        }
+        None => writer.write_all(&[0]).wrap_err("writing response")?,
+    }
+    writer.flush().wrap_err("writing response")?;
+    Ok(())
+}
+
+/// Sends every newly stored item until the peer goes away.
+fn stream_new_items(peer: &mut UnixStream, history: &History) -> eyre::Result<()> {
+    for id in history.subscribe() {
+        let item = history
+            .items
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|item| item.id == id)
+            .cloned();
+        // It may have been evicted right away.
+        let Some(item) = item else {
+            continue;
+        };
+
+        let mut encoded = Vec::new();
+        ciborium::into_writer(&item, &mut encoded).wrap_err("encoding item")?;
+        if clippyboard_shared::write_framed(&mut *peer, &encoded).is_err() {
+            // The peer unsubscribed by closing the connection.
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+fn handle_copy_message(
+    peer: &mut UnixStream,
+    history: &History,
+    clipboard: &impl Clipboard,
+) -> Result<(), eyre::Error> {
+    let mut id = [0; 8];
+    peer.read_exact(&mut id).wrap_err("failed to read id")?;
+    let id = u64::from_le_bytes(id);
+    copy_item(history, clipboard, id)
+}
+
+fn handle_copy_primary_message(
+    peer: &mut UnixStream,
+    history: &History,
+    clipboard: &impl Clipboard,
+) -> eyre::Result<()> {
+    let mut id = [0; 8];
+    peer.read_exact(&mut id).wrap_err("failed to read id")?;
+    let id = u64::from_le_bytes(id);
+    let Some(item) = take_for_copy(history, id) else {
+        return Ok(());
+    };
+
+    clipboard
+        .set_primary_selection(&item.mime, item.data)
+        .wrap_err("doing copy to primary selection")
+}
+
+/// Puts the item on the clipboard and makes it the most recent one.
+/// Does nothing if there is no item with that ID.
+pub fn copy_item(history: &History, clipboard: &impl Clipboard, id: u64) -> eyre::Result<()> {
+    let Some(item) = take_for_copy(history, id) else {
+        return Ok(());
+    };
+
+    clipboard
+        .set_selection(&item.mime, item.data, false)
+        .wrap_err("doing copy")?;
+
+    Ok(())
+}
+
+/// Counts a copy of the item and makes it the most recent one, returning it.
+fn take_for_copy(history: &History, id: u64) -> Option<HistoryItem> {
+    let mut items = history.items.lock().unwrap();
+    let idx = items.iter().position(|item| item.id == id)?;
+    let mut item = items.remove(idx).unwrap();
+    item.copy_count += 1;
+    items.push_back(item.clone());
+    Some(item)
+}
+
+fn handle_store_raw_message(peer: &mut UnixStream, history: &History) -> eyre::Result<()> {
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap();
+
+    let mime = clippyboard_shared::read_framed(&mut *peer, MAX_MIME_SIZE)
+        .wrap_err("failed to read mime type")?;
+    let mime = String::from_utf8(mime).wrap_err("mime type is invalid UTF-8")?;
+    let data = clippyboard_shared::read_framed(&mut *peer, MAX_ENTRY_SIZE)
+        .wrap_err("failed to read data")?;
+
+    let created_time = u64::try_from(time.as_millis()).unwrap();
+    history::insert_item(history, mime, data.into(), created_time)
+}
+
+fn handle_set_selection_message(
+    peer: &mut UnixStream,
+    clipboard: &impl Clipboard,
+) -> eyre::Result<()> {
+    let mime = clippyboard_shared::read_framed(&mut *peer, MAX_MIME_SIZE)
+        .wrap_err("failed to read mime type")?;
+    let mime = String::from_utf8(mime).wrap_err("mime type is invalid UTF-8")?;
+    let data = clippyboard_shared::read_framed(&mut *peer, MAX_ENTRY_SIZE)
+        .wrap_err("failed to read data")?;
+
+    clipboard
+        .set_selection(&mime, data.into(), true)
+        .wrap_err("doing copy")?;
+
+    Ok(())
+}
+
+fn handle_delete_range_message(peer: &mut UnixStream, history: &History) -> eyre::Result<()> {
+    let mut id = [0; 8];
+    peer.read_exact(&mut id).wrap_err("failed to read id")?;
+    let id = u64::from_le_bytes(id);
+    let mut direction = [0; 1];
+    peer.read_exact(&mut direction)
+        .wrap_err("failed to read direction")?;
+
+    let mut items = history.items.lock().unwrap();
+    let mut deleted = Vec::new();
+    if let Some(idx) = items.iter().position(|item| item.id == id) {
+        // oldest first
+        let range = match direction[0] {
+            clippyboard_shared::DELETE_OLDER => 0..idx,
+            clippyboard_shared::DELETE_NEWER => idx + 1..items.len(),
+            direction => bail!("invalid delete direction {direction}"),
+        };
+        let mut position = 0;
+        items.retain(|item| {
+            let keep = !range.contains(&position) || history::is_kept(&history.config, item);
+            if !keep {
+                deleted.push(item.id);
+            }
+            position += 1;
+            keep
+        });
+    }
+    drop(items);
+
+    let mut response = (deleted.len() as u64).to_le_bytes().to_vec();
+    for id in &deleted {
+        response.extend_from_slice(&id.to_le_bytes());
+    }
+    peer.write_all(&response).wrap_err("writing response")?;
+
+    info!(
+        entry_id = id,
+        dropped_count = deleted.len(),
+        "Deleted range of items"
+    );
+    Ok(())
+}
+
+fn handle_tag_message(peer: &mut UnixStream, history: &History, tagged: bool) -> eyre::Result<()> {
+    let mut id = [0; 8];
+    peer.read_exact(&mut id).wrap_err("failed to read id")?;
+    let id = u64::from_le_bytes(id);
+    let tag = clippyboard_shared::read_framed(&mut *peer, MAX_TAG_SIZE).wrap_err("reading tag")?;
+    let tag = String::from_utf8(tag).wrap_err("tag is invalid UTF-8")?;
+
+    if history::set_tag(history, id, &tag, tagged) {
+        info!(entry_id = id, tag, tagged, "Changed tags of item");
+    }
+    Ok(())
+}
+
+fn handle_copy_as_file_message(
+    peer: &mut UnixStream,
+    history: &History,
+    clipboard: &impl Clipboard,
+) -> eyre::Result<()> {
+    let mut id = [0; 8];
+    peer.read_exact(&mut id).wrap_err("failed to read id")?;
+    let id = u64::from_le_bytes(id);
+    let Some(item) = history
+        .items
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|item| item.id == id)
+        .cloned()
+    else {
+        return Ok(());
+    };
+
+    // The MIME type comes from clients, so it must not end up in the path.
+    let Some(&(_, extension)) = FILE_EXTENSIONS.iter().find(|(mime, _)| *mime == item.mime) else {
+        bail!("only images can be copied as a file, not {}", item.mime);
+    };
+
+    let dir = files_dir()?;
+    // Only the file that is currently on the clipboard needs to stay around.
+    if let Some(previous) = history.copied_file.lock().unwrap().take() {
+        let _ = std::fs::remove_file(previous);
+    }
+
+    let path = dir.join(format!("{id}.{extension}"));
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|mut file| file.write_all(&item.data))
+        .wrap_err_with(|| format!("writing {}", path.display()))?;
+    *history.copied_file.lock().unwrap() = Some(path.clone());
+
+    let uri_list = format!("file://{}\r\n", percent_encode_path(&path));
+    clipboard
+        .set_selection("text/uri-list", uri_list.into_bytes().into(), true)
+        .wrap_err("doing copy")?;
+
+    info!(entry_id = id, path = %path.display(), "Copied image as file");
+    Ok(())
+}
+
+/// The image MIME types that can be copied as a file, with the extension of the file.
+const FILE_EXTENSIONS: &[(&str, &str)] = &[
+    ("image/png", "png"),
+    ("image/jpeg", "jpg"),
+    ("image/jpg", "jpg"),
+    ("image/gif", "gif"),
+    ("image/webp", "webp"),
+    ("image/bmp", "bmp"),
+];
+
+/// Returns the directory for images copied as a file, creating it if needed. Without a runtime
+/// directory, it's in the temporary directory that other users share, so it has our UID in its
+/// name and is only used if it's a real directory that only we can access.
+fn files_dir() -> eyre::Result<PathBuf> {
+    let uid = rustix::process::getuid().as_raw();
+    let dir = match dirs::runtime_dir() {
+        Some(dir) => dir.join("clippyboard-files"),
+        None => std::env::temp_dir().join(format!("clippyboard-files-{uid}")),
+    };
+    match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+        Err(err) => return Err(err).wrap_err_with(|| format!("creating {}", dir.display())),
+    }
+
+    let metadata =
+        std::fs::symlink_metadata(&dir).wrap_err_with(|| format!("checking {}", dir.display()))?;
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        bail!(
+            "{} is not a directory that only the current user can access",
+            dir.display()
+        );
+    }
+    Ok(dir)
+}
+
+/// Encodes a path for use in a `file://` URI.
+fn percent_encode_path(path: &Path) -> String {
+    let mut encoded = String::new();
+    for &byte in path.as_os_str().as_bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+fn handle_clear_message(
+    peer: &mut UnixStream,
+    history: &History,
+    clipboard: &impl Clipboard,
+) -> eyre::Result<()> {
+    let mut category = [0; 1];
+    peer.read_exact(&mut category)
+        .wrap_err("failed to read category")?;
+    let mut force = [0; 1];
+    peer.read_exact(&mut force)
+        .wrap_err("failed to read force")?;
+
+    clear(history, clipboard, category[0], force[0] != 0)
+}
+
+fn handle_undo_clear_message(
+    peer: &mut UnixStream,
+    history: &History,
+    clipboard: &impl Clipboard,
+) -> eyre::Result<()> {
+    let restored = history::undo_clear(history);
+    if let Some(Some(current)) = &restored {
+        // Transient, it's already in the history.
+        clipboard
+            .set_selection(&current.mime, current.data.clone(), true)
+            .wrap_err("restoring the clipboard")?;
+    }
+
+    peer.write_all(&[restored.is_some() as u8])
+        .wrap_err("writing response")
+}
+
+/// Removes all items of a `CLEAR_*` category, and the clipboard content if it is one of the
+/// removed items. Kept items are only removed with `force`.
+pub fn clear(
+    history: &History,
+    clipboard: &impl Clipboard,
+    category: u8,
+    force: bool,
+) -> eyre::Result<()> {
+    let mime_prefix = match category {
+        clippyboard_shared::CLEAR_ALL => "",
+        clippyboard_shared::CLEAR_TEXT => "text/",
+        clippyboard_shared::CLEAR_IMAGES => "image/",
+        category => bail!("invalid clear category {category}"),
+    };
+
+    let mut items = history.items.lock().unwrap();
+    // The most recent item is what's currently on the clipboard.
+    let clears_current = items
+        .back()
+        .map_or(category == clippyboard_shared::CLEAR_ALL, |item| {
+            item.mime.starts_with(mime_prefix)
+                && (force || !history::is_kept(&history.config, item))
+        });
+    let old_items = items.clone();
+    items.retain(|item| {
+        !item.mime.starts_with(mime_prefix) || !force && history::is_kept(&history.config, item)
+    });
+    let dropped_count = old_items.len() - items.len();
+    drop(items);
+
+    if history.config.undo_clear_secs > 0 && dropped_count > 0 {
+        *history.cleared.lock().unwrap() = Some((Instant::now(), old_items));
+    }
+
+    if clears_current {
+        clipboard.clear_selection()?;
+    }
+
+    info!(
+        category,
+        force, dropped_count, "Cleared history and clipboard"
+    );
+    Ok(())
+}
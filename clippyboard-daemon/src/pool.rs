@@ -0,0 +1,48 @@
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex, mpsc};
+use tracing::warn;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed number of threads running jobs, queuing jobs while all of them are busy.
+pub struct WorkerPool {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl WorkerPool {
+    pub fn new(name: &str, size: usize) -> Self {
+        let (jobs, recv) = mpsc::channel::<Job>();
+        let recv = Arc::new(Mutex::new(recv));
+
+        for idx in 0..size {
+            let recv = recv.clone();
+            std::thread::Builder::new()
+                .name(format!("{name}-{idx}"))
+                .spawn(move || {
+                    loop {
+                        // Only hold the lock while waiting, not while running the job.
+                        let job = recv.lock().unwrap().recv();
+                        match job {
+                            // A panicking job must not take the worker down with it.
+                            Ok(job) => {
+                                if std::panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                                    warn!("A job panicked");
+                                }
+                            }
+                            // The pool was dropped.
+                            Err(_) => break,
+                        }
+                    }
+                })
+                .expect("failed to spawn worker thread");
+        }
+
+        Self { jobs }
+    }
+
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        self.jobs
+            .send(Box::new(job))
+            .expect("worker threads never exit while the pool exists");
+    }
+}
@@ -0,0 +1,35 @@
+//! Which data control devices a copy is set on.
+//!
+//! Every seat has its own selection, so a copy gets one source per seat. A seat that is removed and
+//! advertised again can briefly have two devices though, the old one until its removal is handled.
+//! Setting the selection on both would make them fight over it, so only the newest device of a
+//! seat is used.
+
+use std::collections::HashMap;
+
+/// Returns the keys of the devices to set the selection on, from each device's key, generation and
+/// seat name. Devices of seats without a name yet are all used, since they can't be told apart.
+///
+/// The generation is counted up whenever a device is requested, so the highest one of a seat is its
+/// newest device. The keys, the seat global names, say nothing about that, since compositors may
+/// reuse them.
+pub fn selection_targets<'a>(
+    devices: impl IntoIterator<Item = (u32, u64, Option<&'a str>)>,
+) -> Vec<u32> {
+    let mut unnamed = Vec::new();
+    let mut newest_by_name = HashMap::<&str, (u64, u32)>::new();
+    for (key, generation, seat_name) in devices {
+        match seat_name {
+            None => unnamed.push(key),
+            Some(seat_name) => {
+                let newest = newest_by_name.entry(seat_name).or_insert((generation, key));
+                *newest = (*newest).max((generation, key));
+            }
+        }
+    }
+
+    let mut targets = unnamed;
+    targets.extend(newest_by_name.into_values().map(|(_, key)| key));
+    targets.sort_unstable();
+    targets
+}
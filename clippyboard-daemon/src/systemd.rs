@@ -0,0 +1,94 @@
+//! Optional integration with systemd, implementing the small parts of `sd_listen_fds(3)` and
+//! `sd_notify(3)` we need. Everything here does nothing when not running under systemd.
+
+use eyre::{Context, bail};
+use rustix::io::FdFlags;
+use rustix::net::{AddressFamily, SocketType};
+use std::os::fd::{BorrowedFd, FromRawFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram, UnixListener};
+
+/// The first file descriptor passed by systemd, see `sd_listen_fds(3)`.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Returns the listening socket passed to us via socket activation, if there is one.
+///
+/// Like `sd_listen_fds(3)` with `unset_environment`, it removes the variables, and the socket is
+/// not inherited by the processes we spawn.
+///
+/// # Safety
+///
+/// It changes the environment, so no other threads may run at the same time.
+pub unsafe fn activated_listener() -> eyre::Result<Option<UnixListener>> {
+    let pid = std::env::var("LISTEN_PID");
+    let fds = std::env::var("LISTEN_FDS");
+    // SAFETY: the caller guarantees that no other threads run.
+    unsafe {
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_FDNAMES");
+    }
+    let (Ok(pid), Ok(fds)) = (pid, fds) else {
+        return Ok(None);
+    };
+    // The variables are inherited by children, so they may not have been meant for us.
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok(None);
+    }
+
+    let fds = fds.parse::<u32>().wrap_err("invalid LISTEN_FDS")?;
+    match fds {
+        0 => return Ok(None),
+        1 => {}
+        _ => bail!("expected a single socket from systemd, got {fds}"),
+    }
+
+    // SAFETY: it's only used while it's checked, after which it's either owned by the listener or
+    // left alone.
+    let fd = unsafe { BorrowedFd::borrow_raw(SD_LISTEN_FDS_START) };
+    check_listener(fd).wrap_err("the file descriptor passed by systemd is unusable")?;
+    rustix::io::fcntl_setfd(fd, FdFlags::CLOEXEC)
+        .wrap_err("setting close-on-exec on the socket passed by systemd")?;
+    // SAFETY: systemd passes the file descriptor to us, and it's a listening socket.
+    Ok(Some(unsafe {
+        UnixListener::from_raw_fd(SD_LISTEN_FDS_START)
+    }))
+}
+
+/// Checks that `fd` is a listening Unix stream socket.
+fn check_listener(fd: BorrowedFd<'_>) -> eyre::Result<()> {
+    let family = rustix::net::getsockname(fd)
+        .wrap_err("getting the socket address")?
+        .address_family();
+    if family != AddressFamily::UNIX {
+        bail!("it is not a Unix socket");
+    }
+    if rustix::net::sockopt::socket_type(fd).wrap_err("getting the socket type")?
+        != SocketType::STREAM
+    {
+        bail!("it is not a stream socket");
+    }
+    if !rustix::net::sockopt::socket_acceptconn(fd).wrap_err("checking if the socket listens")? {
+        bail!("the socket is not listening");
+    }
+    Ok(())
+}
+
+/// Tells systemd that we are ready to handle requests.
+pub fn notify_ready() -> eyre::Result<()> {
+    let Some(notify_socket) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+
+    let addr = match notify_socket.as_encoded_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(&notify_socket),
+    }
+    .wrap_err("invalid NOTIFY_SOCKET")?;
+
+    let socket = UnixDatagram::unbound().wrap_err("creating notify socket")?;
+    socket
+        .send_to_addr(b"READY=1", &addr)
+        .wrap_err("sending readiness notification")?;
+    Ok(())
+}
@@ -0,0 +1,42 @@
+use clippyboard_shared::exit::Failure;
+use std::os::unix::net::UnixListener;
+use std::process::Command;
+
+#[test]
+fn socket_in_use() {
+    let socket_path = std::env::temp_dir().join(format!(
+        "clippyboard-test-in-use-{}.sock",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&socket_path);
+    let _listener = UnixListener::bind(&socket_path).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_clippyboard-daemon"))
+        .arg("--allow-root")
+        .env("CLIPPYBOARD_SOCKET", &socket_path)
+        .env("CLIPPYBOARD_CONFIG", "/dev/null")
+        .output()
+        .unwrap();
+    assert_eq!(
+        output.status.code(),
+        Some(Failure::SocketInUse.exit_code().into())
+    );
+    // It belongs to the other daemon.
+    assert!(socket_path.exists());
+
+    std::fs::remove_file(&socket_path).unwrap();
+}
+
+#[test]
+fn daemon_not_running() {
+    let socket_path = std::env::temp_dir().join(format!(
+        "clippyboard-test-not-running-{}.sock",
+        std::process::id()
+    ));
+
+    let err = clippyboard_shared::ping(&socket_path).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<Failure>(),
+        Some(&Failure::DaemonNotRunning)
+    );
+}
@@ -0,0 +1,95 @@
+#![cfg(feature = "http")]
+
+use clippyboard_daemon::history::{self, History};
+use clippyboard_daemon::http;
+use clippyboard_shared::config::DaemonConfig;
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+
+const PORT: u16 = 7171;
+
+fn get(history: &History, request: &str) -> String {
+    let (mut client, server) = UnixStream::pair().unwrap();
+    client.write_all(request.as_bytes()).unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    http::handle_connection(history, PORT, server).unwrap();
+
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn history_and_items() {
+    let history = History::new(DaemonConfig::default());
+    history::insert_item(&history, "text/plain".into(), b"meow".as_slice().into(), 0).unwrap();
+
+    let response = get(
+        &history,
+        "GET /history HTTP/1.1\r\nHost: localhost:7171\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("Content-Type: application/json\r\n"));
+    let body = response.split_once("\r\n\r\n").unwrap().1;
+    let items: serde_json::Value = serde_json::from_str(body).unwrap();
+    assert_eq!(items[0]["preview"], "meow");
+
+    let response = get(
+        &history,
+        "GET /items/0 HTTP/1.1\r\nHost: 127.0.0.1:7171\r\n\r\n",
+    );
+    assert!(response.contains("Content-Type: text/plain\r\n"));
+    assert!(response.contains("X-Content-Type-Options: nosniff\r\n"));
+    assert!(response.ends_with("\r\n\r\nmeow"));
+
+    let response = get(
+        &history,
+        "GET /items/1 HTTP/1.1\r\nHost: localhost:7171\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    let response = get(
+        &history,
+        "DELETE /items/0 HTTP/1.1\r\nHost: localhost:7171\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+}
+
+#[test]
+fn other_hosts_are_rejected() {
+    let history = History::new(DaemonConfig::default());
+    history::insert_item(&history, "text/plain".into(), b"meow".as_slice().into(), 0).unwrap();
+
+    for request in [
+        "GET /items/0 HTTP/1.1\r\nHost: attacker.example:7171\r\n\r\n",
+        "GET /items/0 HTTP/1.1\r\nHost: localhost:8080\r\n\r\n",
+        "GET /items/0 HTTP/1.1\r\n\r\n",
+    ] {
+        let response = get(&history, request);
+        assert!(
+            response.starts_with("HTTP/1.1 403 Forbidden\r\n"),
+            "{request}"
+        );
+        assert!(!response.contains("meow"));
+    }
+}
+
+#[test]
+fn invalid_mime_types_are_not_sent_as_headers() {
+    let history = History::new(DaemonConfig::default());
+    history::insert_item(
+        &history,
+        "image/x-evil\r\nSet-Cookie: a=b".into(),
+        b"meow".as_slice().into(),
+        0,
+    )
+    .unwrap();
+
+    let response = get(
+        &history,
+        "GET /items/0 HTTP/1.1\r\nHost: localhost:7171\r\n\r\n",
+    );
+    assert!(response.contains("Content-Type: application/octet-stream\r\n"));
+    assert!(!response.contains("Set-Cookie"));
+}
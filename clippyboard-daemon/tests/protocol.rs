@@ -0,0 +1,1099 @@
+use clippyboard_daemon::history::{self, History, MAX_HISTORY_BYTE_SIZE};
+use clippyboard_daemon::peer::{self, Clipboard};
+use clippyboard_shared::PROTOCOL_VERSION;
+use clippyboard_shared::config::{DaemonConfig, DedupMode};
+use clippyboard_shared::{HistoryItem, HistoryItemMeta};
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Default)]
+struct MockClipboard {
+    selections: Mutex<Vec<(String, Vec<u8>, bool)>>,
+    clear_count: Mutex<usize>,
+    primary_selections: Mutex<Vec<(String, Vec<u8>)>>,
+}
+
+impl Clipboard for MockClipboard {
+    fn set_selection(&self, mime: &str, data: Arc<[u8]>, transient: bool) -> eyre::Result<()> {
+        self.selections
+            .lock()
+            .unwrap()
+            .push((mime.to_owned(), data.to_vec(), transient));
+        Ok(())
+    }
+
+    fn set_primary_selection(&self, mime: &str, data: Arc<[u8]>) -> eyre::Result<()> {
+        self.primary_selections
+            .lock()
+            .unwrap()
+            .push((mime.to_owned(), data.to_vec()));
+        Ok(())
+    }
+
+    fn clear_selection(&self) -> eyre::Result<()> {
+        *self.clear_count.lock().unwrap() += 1;
+        Ok(())
+    }
+}
+
+/// A daemon without a Wayland connection, handling each request on a new connection.
+struct Daemon {
+    history: History,
+    clipboard: MockClipboard,
+}
+
+impl Daemon {
+    fn new(config: DaemonConfig) -> Self {
+        Self {
+            history: History::new(config),
+            clipboard: MockClipboard::default(),
+        }
+    }
+
+    /// Sends `request` to a peer handler and returns everything it answered after the handshake.
+    fn request(&self, request: &[u8]) -> Vec<u8> {
+        let response = self.raw_request(PROTOCOL_VERSION, request).unwrap();
+        assert_eq!(response[0], PROTOCOL_VERSION);
+        response[1..].to_vec()
+    }
+
+    fn raw_request(&self, version: u8, request: &[u8]) -> eyre::Result<Vec<u8>> {
+        let (mut client, server) = UnixStream::pair().unwrap();
+
+        std::thread::scope(|scope| {
+            let handler = scope.spawn(|| peer::handle_peer(server, &self.history, &self.clipboard));
+
+            client.write_all(&[version]).unwrap();
+            client.write_all(request).unwrap();
+            client.shutdown(Shutdown::Write).unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).unwrap();
+
+            handler.join().unwrap().map(|()| response)
+        })
+    }
+
+    fn store(&self, mime: &str, data: &[u8]) {
+        self.request(&store_raw_request(mime, data));
+    }
+
+    fn read_items(&self) -> Vec<HistoryItem> {
+        let response = self.request(&[clippyboard_shared::MESSAGE_READ]);
+        ciborium::from_reader(response.as_slice()).unwrap()
+    }
+}
+
+fn store_raw_request(mime: &str, data: &[u8]) -> Vec<u8> {
+    let mut request = vec![clippyboard_shared::MESSAGE_STORE_RAW];
+    clippyboard_shared::write_framed(&mut request, mime.as_bytes()).unwrap();
+    clippyboard_shared::write_framed(&mut request, data).unwrap();
+    request
+}
+
+#[test]
+fn store_and_read() {
+    let daemon = Daemon::new(DaemonConfig::default());
+
+    daemon.store("text/plain", b"meow");
+    daemon.store("image/png", b"\x89PNG");
+
+    let items = daemon.read_items();
+    assert_eq!(items.len(), 2);
+    assert_eq!(items[0].mime, "text/plain");
+    assert_eq!(&*items[0].data, b"meow");
+    assert_eq!(items[1].mime, "image/png");
+    assert_eq!(&*items[1].data, b"\x89PNG");
+}
+
+#[test]
+fn binary_data_round_trips() {
+    let daemon = Daemon::new(DaemonConfig::default());
+    // a PNG header followed by every byte value, including invalid UTF-8 and NUL
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    png.extend((0..=255).cycle().take(100_000));
+
+    daemon.store("image/png", &png);
+    let items = daemon.read_items();
+    assert_eq!(&*items[0].data, png);
+    assert_eq!(items[0].hash, xxhash_rust::xxh3::xxh3_64(&png));
+
+    let mut copy = vec![clippyboard_shared::MESSAGE_COPY];
+    copy.extend_from_slice(&items[0].id.to_le_bytes());
+    daemon.request(&copy);
+
+    let selections = daemon.clipboard.selections.lock().unwrap();
+    assert_eq!(selections[0].0, "image/png");
+    assert_eq!(selections[0].1, png);
+}
+
+#[test]
+fn multiple_messages_on_one_connection() {
+    let daemon = Daemon::new(DaemonConfig::default());
+
+    let mut requests = store_raw_request("text/plain", b"meow");
+    requests.extend(store_raw_request("text/plain", b"woof"));
+    requests.push(clippyboard_shared::MESSAGE_READ);
+    let response = daemon.request(&requests);
+
+    let items: Vec<HistoryItem> = ciborium::from_reader(response.as_slice()).unwrap();
+    assert_eq!(items.len(), 2);
+}
+
+#[test]
+fn read_meta_and_get() {
+    let daemon = Daemon::new(DaemonConfig::default());
+    daemon.store("text/plain", "miau 🐈".as_bytes());
+    daemon.store("image/png", b"\x89PNG");
+
+    let mut read_meta = vec![clippyboard_shared::MESSAGE_READ_META];
+    read_meta.extend_from_slice(&6_u64.to_le_bytes());
+    let response = daemon.request(&read_meta);
+    let items: Vec<HistoryItemMeta> = ciborium::from_reader(response.as_slice()).unwrap();
+    assert_eq!(items.len(), 2);
+    assert_eq!(items[0].preview.as_deref(), Some("miau 🐈"));
+    assert_eq!(items[0].size, "miau 🐈".len() as u64);
+    assert_eq!(items[1].mime, "image/png");
+    assert_eq!(items[1].preview, None);
+    assert_eq!(items[1].size, 4);
+
+    let mut get = vec![clippyboard_shared::MESSAGE_GET];
+    get.extend_from_slice(&items[1].id.to_le_bytes());
+    get.push(clippyboard_shared::MESSAGE_GET);
+    get.extend_from_slice(&1000_u64.to_le_bytes());
+    let response = daemon.request(&get);
+    assert_eq!(response[0], 1);
+    let data = clippyboard_shared::read_framed(&response[1..], u64::MAX).unwrap();
+    assert_eq!(data, b"\x89PNG");
+    assert_eq!(&response[1 + 8 + 4..], [0]);
+}
+
+/// The field names are the wire format that other clients rely on, so they must not change.
+#[test]
+fn wire_field_names_are_stable() {
+    let daemon = Daemon::new(DaemonConfig::default());
+    daemon.store("text/plain", b"meow");
+
+    let keys = |response: Vec<u8>| {
+        let value: ciborium::Value = ciborium::from_reader(response.as_slice()).unwrap();
+        let items = value.into_array().unwrap();
+        let map = items[0].clone().into_map().unwrap();
+        map.into_iter()
+            .map(|(key, _)| key.into_text().unwrap())
+            .collect::<Vec<_>>()
+    };
+
+    let response = daemon.request(&[clippyboard_shared::MESSAGE_READ]);
+    assert_eq!(
+        keys(response),
+        [
+            "id",
+            "mime",
+            "data",
+            "created_time",
+            "hash",
+            "copy_count",
+            "seat",
+            "original_mime",
+            "tags",
+            "thumbnail",
+            "source"
+        ]
+    );
+
+    let mut read_meta = vec![clippyboard_shared::MESSAGE_READ_META];
+    read_meta.extend_from_slice(&10_u64.to_le_bytes());
+    let response = daemon.request(&read_meta);
+    assert_eq!(
+        keys(response),
+        [
+            "id",
+            "mime",
+            "size",
+            "created_time",
+            "hash",
+            "copy_count",
+            "seat",
+            "preview",
+            "tags",
+            "thumbnail",
+            "source"
+        ]
+    );
+}
+
+#[test]
+fn identical_items_are_deduplicated() {
+    let history = History::new(DaemonConfig::default());
+
+    history::insert_item(&history, "text/plain".into(), b"meow".as_slice().into(), 0).unwrap();
+    history::insert_item(&history, "text/plain".into(), b"meow".as_slice().into(), 1).unwrap();
+    assert_eq!(history.items.lock().unwrap().len(), 1);
+
+    history::insert_item(&history, "text/plain".into(), b"woof".as_slice().into(), 2).unwrap();
+    history::insert_item(&history, "text/plain".into(), b"meow".as_slice().into(), 3).unwrap();
+    assert_eq!(history.items.lock().unwrap().len(), 3);
+}
+
+#[test]
+fn items_evicted_for_their_size_are_not_stored_again() {
+    let history = History::new(DaemonConfig {
+        max_image_bytes: Some(10),
+        ..Default::default()
+    });
+    let new_items = history.subscribe();
+
+    let image = [0; 100];
+    history::insert_item(&history, "image/png".into(), image.as_slice().into(), 0).unwrap();
+    assert!(history.items.lock().unwrap().is_empty());
+    history::insert_item(&history, "image/png".into(), image.as_slice().into(), 0).unwrap();
+    assert_eq!(new_items.try_iter().count(), 1);
+
+    // Something else in between ends it.
+    history::insert_item(&history, "text/plain".into(), b"meow".as_slice().into(), 0).unwrap();
+    history::insert_item(&history, "image/png".into(), image.as_slice().into(), 0).unwrap();
+    assert_eq!(new_items.try_iter().count(), 2);
+}
+
+#[test]
+fn dedup_can_ignore_mime() {
+    let history = History::new(DaemonConfig {
+        dedup: DedupMode::IgnoreMime,
+        ..Default::default()
+    });
+
+    history::insert_item(&history, "image/jpg".into(), b"\xff".as_slice().into(), 0).unwrap();
+    history::insert_item(&history, "image/png".into(), b"\xff".as_slice().into(), 1).unwrap();
+
+    let items = history.items.lock().unwrap();
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].mime, "image/png");
+}
+
+#[test]
+fn old_items_are_evicted() {
+    let history = History::new(DaemonConfig::default());
+    let data = |byte: u8| -> Arc<[u8]> { vec![byte; MAX_HISTORY_BYTE_SIZE / 3].into() };
+
+    for byte in 0..4 {
+        history::insert_item(&history, "image/png".into(), data(byte), 0).unwrap();
+    }
+
+    let items = history.items.lock().unwrap();
+    assert_eq!(items.len(), 2);
+    assert_eq!(items[0].data[0], 2);
+    assert_eq!(items[1].data[0], 3);
+}
+
+#[test]
+fn quotas_only_evict_their_own_category() {
+    // room for two images, including the size of the item itself
+    let history = History::new(DaemonConfig {
+        max_image_bytes: Some(2 * (400 + std::mem::size_of::<HistoryItem>())),
+        ..Default::default()
+    });
+
+    history::insert_item(&history, "text/plain".into(), b"meow".as_slice().into(), 0).unwrap();
+    for byte in 0..4 {
+        history::insert_item(&history, "image/png".into(), vec![byte; 400].into(), 0).unwrap();
+    }
+
+    let items = history.items.lock().unwrap();
+    let mimes = items
+        .iter()
+        .map(|item| item.mime.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(mimes, ["text/plain", "image/png", "image/png"]);
+    assert_eq!(items[1].data[0], 2);
+    assert_eq!(items[2].data[0], 3);
+}
+
+#[test]
+fn blank_text_is_skipped_when_configured() {
+    let history = History::new(DaemonConfig {
+        skip_blank_text: true,
+        ..Default::default()
+    });
+
+    history::insert_item(&history, "text/plain".into(), b" \n\t".as_slice().into(), 0).unwrap();
+    assert!(history.items.lock().unwrap().is_empty());
+
+    history::insert_item(
+        &history,
+        "text/plain".into(),
+        b" meow\n".as_slice().into(),
+        0,
+    )
+    .unwrap();
+    assert_eq!(&*history.items.lock().unwrap()[0].data, b" meow\n");
+}
+
+#[test]
+fn short_text_is_skipped_when_configured() {
+    let history = History::new(DaemonConfig {
+        min_text_bytes: 2,
+        ..Default::default()
+    });
+
+    history::insert_item(&history, "text/plain".into(), b"a".as_slice().into(), 0).unwrap();
+    history::insert_item(&history, "image/png".into(), b"a".as_slice().into(), 0).unwrap();
+    history::insert_item(&history, "text/plain".into(), b"ab".as_slice().into(), 0).unwrap();
+
+    let items = history.items.lock().unwrap();
+    assert_eq!(items.len(), 2);
+    assert_eq!(items[0].mime, "image/png");
+    assert_eq!(&*items[1].data, b"ab");
+}
+
+#[test]
+fn growing_text_is_merged_when_configured() {
+    let history = History::new(DaemonConfig {
+        merge_text_within_ms: Some(1000),
+        ..Default::default()
+    });
+    let insert = |data: &[u8], time| {
+        history::insert_item(&history, "text/plain".into(), data.into(), time).unwrap();
+    };
+
+    insert(b"me", 0);
+    insert(b"meow", 500);
+    insert(b"meow meow", 900);
+    // too late
+    insert(b"meow meow!", 5000);
+    // unrelated
+    insert(b"woof", 5100);
+
+    let items = history.items.lock().unwrap();
+    let data = items.iter().map(|item| &*item.data).collect::<Vec<_>>();
+    assert_eq!(data, [b"meow meow".as_slice(), b"meow meow!", b"woof"]);
+}
+
+#[test]
+fn subscribers_get_new_item_ids() {
+    let history = History::new(DaemonConfig::default());
+    let new_items = history.subscribe();
+
+    history::insert_item(&history, "text/plain".into(), b"meow".as_slice().into(), 0).unwrap();
+    // deduplicated
+    history::insert_item(&history, "text/plain".into(), b"meow".as_slice().into(), 0).unwrap();
+
+    let id = history.items.lock().unwrap()[0].id;
+    assert_eq!(new_items.try_iter().collect::<Vec<_>>(), [id]);
+
+    assert!(history::remove_item(&history, id));
+    assert!(!history::remove_item(&history, id));
+}
+
+#[test]
+fn old_items_expire() {
+    let history = History::new(DaemonConfig {
+        max_age_secs: Some(60),
+        ..Default::default()
+    });
+
+    history::insert_item(&history, "text/plain".into(), b"old".as_slice().into(), 0).unwrap();
+    history::insert_item(
+        &history,
+        "text/plain".into(),
+        b"new".as_slice().into(),
+        50_000,
+    )
+    .unwrap();
+    history::remove_expired_items(&history, 70_000);
+
+    let items = history.items.lock().unwrap();
+    assert_eq!(items.len(), 1);
+    assert_eq!(&*items[0].data, b"new");
+}
+
+#[test]
+fn items_are_kept_without_max_age() {
+    let history = History::new(DaemonConfig::default());
+
+    history::insert_item(&history, "text/plain".into(), b"old".as_slice().into(), 0).unwrap();
+    history::remove_expired_items(&history, u64::MAX);
+
+    assert_eq!(history.items.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn copy_moves_item_to_the_end() {
+    let daemon = Daemon::new(DaemonConfig::default());
+
+    daemon.store("text/plain", b"first");
+    daemon.store("text/plain", b"second");
+    let first_id = daemon.read_items()[0].id;
+
+    let mut copy = vec![clippyboard_shared::MESSAGE_COPY];
+    copy.extend_from_slice(&first_id.to_le_bytes());
+    daemon.request(&copy);
+
+    let items = daemon.read_items();
+    assert_eq!(&*items[1].data, b"first");
+    assert_eq!(items[1].copy_count, 1);
+    assert_eq!(
+        *daemon.clipboard.selections.lock().unwrap(),
+        [("text/plain".to_owned(), b"first".to_vec(), false)]
+    );
+}
+
+#[test]
+fn copy_to_primary_selection() {
+    let daemon = Daemon::new(DaemonConfig::default());
+    daemon.store("text/plain", b"meow");
+    let id = daemon.read_items()[0].id;
+
+    let mut copy = vec![clippyboard_shared::MESSAGE_COPY_PRIMARY];
+    copy.extend_from_slice(&id.to_le_bytes());
+    daemon.request(&copy);
+
+    assert_eq!(
+        *daemon.clipboard.primary_selections.lock().unwrap(),
+        [("text/plain".to_owned(), b"meow".to_vec())]
+    );
+    assert!(daemon.clipboard.selections.lock().unwrap().is_empty());
+    assert_eq!(daemon.read_items()[0].copy_count, 1);
+}
+
+#[test]
+fn set_selection_is_transient() {
+    let daemon = Daemon::new(DaemonConfig::default());
+
+    let mut set_selection = vec![clippyboard_shared::MESSAGE_SET_SELECTION];
+    clippyboard_shared::write_framed(&mut set_selection, b"text/plain").unwrap();
+    clippyboard_shared::write_framed(&mut set_selection, b"meow").unwrap();
+    daemon.request(&set_selection);
+
+    assert!(daemon.history.items.lock().unwrap().is_empty());
+    assert_eq!(
+        *daemon.clipboard.selections.lock().unwrap(),
+        [("text/plain".to_owned(), b"meow".to_vec(), true)]
+    );
+}
+
+#[test]
+fn copy_image_as_file() {
+    let daemon = Daemon::new(DaemonConfig::default());
+
+    daemon.store("image/png", b"\x89PNG");
+    let id = daemon.read_items()[0].id;
+
+    let mut copy = vec![clippyboard_shared::MESSAGE_COPY_AS_FILE];
+    copy.extend_from_slice(&id.to_le_bytes());
+    daemon.request(&copy);
+
+    let selections = daemon.clipboard.selections.lock().unwrap();
+    let (mime, uri_list, transient) = &selections[0];
+    assert_eq!(mime, "text/uri-list");
+    assert!(transient);
+    let path = str::from_utf8(uri_list)
+        .unwrap()
+        .strip_prefix("file://")
+        .unwrap()
+        .strip_suffix("\r\n")
+        .unwrap();
+    assert!(path.ends_with(&format!("/{id}.png")));
+    assert_eq!(std::fs::read(path).unwrap(), b"\x89PNG");
+    let mode = std::fs::metadata(path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+}
+
+#[test]
+fn copy_as_file_does_not_use_the_mime_type_in_the_path() {
+    let daemon = Daemon::new(DaemonConfig::default());
+
+    daemon.store("image/../../meow", b"\x89PNG");
+    let id = daemon.read_items()[0].id;
+
+    let mut copy = vec![clippyboard_shared::MESSAGE_COPY_AS_FILE];
+    copy.extend_from_slice(&id.to_le_bytes());
+    assert!(daemon.raw_request(PROTOCOL_VERSION, &copy).is_err());
+
+    assert!(daemon.clipboard.selections.lock().unwrap().is_empty());
+}
+
+#[test]
+fn copy_as_file_removes_the_previous_file() {
+    let daemon = Daemon::new(DaemonConfig::default());
+
+    daemon.store("image/png", b"\x89PNG");
+    daemon.store("image/gif", b"GIF89a");
+    for item in daemon.read_items() {
+        let mut copy = vec![clippyboard_shared::MESSAGE_COPY_AS_FILE];
+        copy.extend_from_slice(&item.id.to_le_bytes());
+        daemon.request(&copy);
+    }
+
+    let selections = daemon.clipboard.selections.lock().unwrap();
+    let paths = selections
+        .iter()
+        .map(|(_, uri_list, _)| {
+            let uri_list = str::from_utf8(uri_list).unwrap();
+            uri_list
+                .strip_prefix("file://")
+                .unwrap()
+                .trim_end()
+                .to_owned()
+        })
+        .collect::<Vec<_>>();
+    assert!(!std::path::Path::new(&paths[0]).exists());
+    assert!(paths[1].ends_with(".gif"));
+    assert_eq!(std::fs::read(&paths[1]).unwrap(), b"GIF89a");
+}
+
+#[test]
+fn delete_range() {
+    let daemon = Daemon::new(DaemonConfig::default());
+
+    for data in [b"1", b"2", b"3", b"4"] {
+        daemon.store("text/plain", data);
+    }
+    let id = |items: &[HistoryItem], data: &[u8]| {
+        items.iter().find(|item| &*item.data == data).unwrap().id
+    };
+    let delete_range = |id: u64, direction: u8| {
+        let mut delete = vec![clippyboard_shared::MESSAGE_DELETE_RANGE];
+        delete.extend_from_slice(&id.to_le_bytes());
+        delete.push(direction);
+        daemon.request(&delete)
+    };
+
+    let items = daemon.read_items();
+    let mut deleted = 1u64.to_le_bytes().to_vec();
+    deleted.extend_from_slice(&id(&items, b"1").to_le_bytes());
+    assert_eq!(
+        delete_range(id(&items, b"2"), clippyboard_shared::DELETE_OLDER),
+        deleted
+    );
+    let mut deleted = 1u64.to_le_bytes().to_vec();
+    deleted.extend_from_slice(&id(&items, b"4").to_le_bytes());
+    assert_eq!(
+        delete_range(id(&items, b"3"), clippyboard_shared::DELETE_NEWER),
+        deleted
+    );
+
+    let items = daemon.read_items();
+    let data = items.iter().map(|item| &*item.data).collect::<Vec<_>>();
+    assert_eq!(data, [b"2", b"3"]);
+}
+
+#[test]
+fn clear() {
+    let daemon = Daemon::new(DaemonConfig::default());
+
+    daemon.store("text/plain", b"meow");
+    daemon.request(&[
+        clippyboard_shared::MESSAGE_CLEAR,
+        clippyboard_shared::CLEAR_ALL,
+        0,
+    ]);
+
+    assert!(daemon.read_items().is_empty());
+    assert_eq!(*daemon.clipboard.clear_count.lock().unwrap(), 1);
+}
+
+#[test]
+fn undo_clear() {
+    let daemon = Daemon::new(DaemonConfig::default());
+    daemon.store("text/plain", b"meow");
+
+    let undo = [clippyboard_shared::MESSAGE_UNDO_CLEAR];
+    assert_eq!(daemon.request(&undo), [0]);
+
+    daemon.request(&[
+        clippyboard_shared::MESSAGE_CLEAR,
+        clippyboard_shared::CLEAR_ALL,
+        0,
+    ]);
+    assert!(daemon.read_items().is_empty());
+
+    assert_eq!(daemon.request(&undo), [1]);
+    let items = daemon.read_items();
+    assert_eq!(items.len(), 1);
+    assert_eq!(&*items[0].data, b"meow");
+    assert_eq!(
+        *daemon.clipboard.selections.lock().unwrap(),
+        [("text/plain".to_owned(), b"meow".to_vec(), true)]
+    );
+
+    // Only once.
+    assert_eq!(daemon.request(&undo), [0]);
+}
+
+#[test]
+fn storing_ends_undo_clear() {
+    let daemon = Daemon::new(DaemonConfig::default());
+    daemon.store("text/plain", b"meow");
+    daemon.request(&[
+        clippyboard_shared::MESSAGE_CLEAR,
+        clippyboard_shared::CLEAR_ALL,
+        0,
+    ]);
+    daemon.store("text/plain", b"woof");
+
+    let undo = [clippyboard_shared::MESSAGE_UNDO_CLEAR];
+    assert_eq!(daemon.request(&undo), [0]);
+    let items = daemon.read_items();
+    assert_eq!(items.len(), 1);
+    assert_eq!(&*items[0].data, b"woof");
+}
+
+#[test]
+fn clear_only_images() {
+    let daemon = Daemon::new(DaemonConfig::default());
+
+    daemon.store("image/png", b"\x89PNG");
+    daemon.store("text/plain", b"meow");
+    daemon.request(&[
+        clippyboard_shared::MESSAGE_CLEAR,
+        clippyboard_shared::CLEAR_IMAGES,
+        0,
+    ]);
+
+    let items = daemon.read_items();
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].mime, "text/plain");
+    // the text is still on the clipboard
+    assert_eq!(*daemon.clipboard.clear_count.lock().unwrap(), 0);
+}
+
+#[test]
+fn version_mismatch_is_answered_with_our_version() {
+    let daemon = Daemon::new(DaemonConfig::default());
+
+    let response = daemon.raw_request(PROTOCOL_VERSION + 1, &[]).unwrap();
+
+    assert_eq!(response, [PROTOCOL_VERSION]);
+}
+
+#[test]
+fn stalled_capture_times_out() {
+    let history = History::new(DaemonConfig::default());
+    let (reader, mut writer) = std::io::pipe().unwrap();
+    writer.write_all(b"partial").unwrap();
+
+    let reader = history::StallTimeout {
+        inner: reader,
+        timeout: Duration::from_millis(50),
+    };
+    let result = history::read_fd_into_history(
+        &history,
+        Duration::ZERO,
+        "text/plain".into(),
+        None,
+        None,
+        reader,
+    );
+
+    assert!(result.is_err());
+    assert!(history.items.lock().unwrap().is_empty());
+    drop(writer);
+}
+
+#[test]
+fn worker_pool_runs_queued_jobs() {
+    let pool = clippyboard_daemon::pool::WorkerPool::new("test", 2);
+    let (send, recv) = std::sync::mpsc::channel();
+
+    pool.execute(|| panic!("the worker survives this"));
+    for idx in 0..10 {
+        let send = send.clone();
+        pool.execute(move || send.send(idx).unwrap());
+    }
+
+    let mut results = recv.iter().take(10).collect::<Vec<_>>();
+    results.sort();
+    assert_eq!(results, (0..10).collect::<Vec<_>>());
+}
+
+#[test]
+fn source_is_guessed_from_mime_types() {
+    let firefox = ["text/plain", "text/html", "text/x-moz-url-priv"];
+    assert_eq!(history::source_from_mime_types(firefox), Some("Firefox"));
+    assert_eq!(history::source_from_mime_types(["text/plain"]), None);
+
+    let daemon = Daemon::new(DaemonConfig::default());
+    history::read_fd_into_history(
+        &daemon.history,
+        Duration::ZERO,
+        "text/plain".into(),
+        None,
+        Some("Firefox".into()),
+        &b"meow"[..],
+    )
+    .unwrap();
+    assert_eq!(daemon.read_items()[0].source.as_deref(), Some("Firefox"));
+}
+
+#[test]
+fn utf8_text_mimes() {
+    assert!(history::is_utf8_text_mime("text/plain"));
+    assert!(history::is_utf8_text_mime("text/plain;charset=utf-8"));
+    assert!(history::is_utf8_text_mime("text/plain; charset=\"UTF-8\""));
+    assert!(!history::is_utf8_text_mime("text/plain;charset=utf-16"));
+    assert!(!history::is_utf8_text_mime("text/html;charset=utf-8"));
+}
+
+#[test]
+fn mime_patterns() {
+    assert!(history::matches_pattern("text/plain", "text/plain"));
+    assert!(!history::matches_pattern(
+        "text/plain",
+        "text/plain;charset=utf-8"
+    ));
+    assert!(history::matches_pattern(
+        "application/x-*",
+        "application/x-secret"
+    ));
+    assert!(history::matches_pattern(
+        "application/x-*",
+        "application/x-"
+    ));
+    assert!(!history::matches_pattern(
+        "application/x-*",
+        "text/x-secret"
+    ));
+    assert!(history::matches_pattern("*/secret", "text/secret"));
+    assert!(!history::matches_pattern("*/secret", "text/secrets"));
+    assert!(history::matches_pattern(
+        "x-*-hint*",
+        "x-kde-passwordManager-hint"
+    ));
+    assert!(history::matches_pattern("a*b*c", "abbc"));
+    assert!(!history::matches_pattern("a*b*c", "acb"));
+    // The last part must not overlap with the part before it.
+    assert!(!history::matches_pattern("ab*ba", "aba"));
+    assert!(history::matches_pattern("*", ""));
+}
+
+#[test]
+fn ignored_mimes() {
+    let config = DaemonConfig {
+        ignored_mime_types: vec!["x-kde-passwordManagerHint".to_owned()],
+        ignored_mime_patterns: vec!["application/x-secret-*".to_owned()],
+        ..Default::default()
+    };
+    assert!(history::is_ignored_mime(
+        &config,
+        "x-kde-passwordManagerHint"
+    ));
+    assert!(history::is_ignored_mime(
+        &config,
+        "application/x-secret-token"
+    ));
+    assert!(!history::is_ignored_mime(&config, "text/plain"));
+}
+
+#[test]
+fn text_with_charset_is_stored_as_text_plain() {
+    let daemon = Daemon::new(DaemonConfig::default());
+
+    history::read_fd_into_history(
+        &daemon.history,
+        Duration::ZERO,
+        "text/plain;charset=utf-8".to_owned(),
+        None,
+        None,
+        &b"meow"[..],
+    )
+    .unwrap();
+
+    let items = daemon.read_items();
+    assert_eq!(items[0].mime, "text/plain");
+    assert_eq!(
+        items[0].original_mime.as_deref(),
+        Some("text/plain;charset=utf-8")
+    );
+
+    let mut copy = vec![clippyboard_shared::MESSAGE_COPY];
+    copy.extend_from_slice(&items[0].id.to_le_bytes());
+    daemon.request(&copy);
+    assert_eq!(
+        daemon.clipboard.selections.lock().unwrap()[0].0,
+        "text/plain"
+    );
+}
+
+#[test]
+fn ping() {
+    let daemon = Daemon::new(DaemonConfig::default());
+
+    let response = daemon.request(&[
+        clippyboard_shared::MESSAGE_PING,
+        clippyboard_shared::MESSAGE_PING,
+    ]);
+    assert_eq!(
+        response,
+        [
+            clippyboard_shared::MESSAGE_PING,
+            clippyboard_shared::MESSAGE_PING
+        ]
+    );
+}
+
+fn tag_request(message: u8, id: u64, tag: &str) -> Vec<u8> {
+    let mut request = vec![message];
+    request.extend_from_slice(&id.to_le_bytes());
+    clippyboard_shared::write_framed(&mut request, tag.as_bytes()).unwrap();
+    request
+}
+
+#[test]
+fn tags() {
+    let daemon = Daemon::new(DaemonConfig::default());
+    daemon.store("text/plain", b"meow");
+    let id = daemon.read_items()[0].id;
+
+    let mut requests = tag_request(clippyboard_shared::MESSAGE_TAG, id, "cat");
+    requests.extend(tag_request(clippyboard_shared::MESSAGE_TAG, id, "cat"));
+    requests.extend(tag_request(clippyboard_shared::MESSAGE_TAG, id, "sound"));
+    daemon.request(&requests);
+    assert_eq!(daemon.read_items()[0].tags, ["cat", "sound"]);
+
+    daemon.request(&tag_request(clippyboard_shared::MESSAGE_UNTAG, id, "cat"));
+    assert_eq!(daemon.read_items()[0].tags, ["sound"]);
+}
+
+#[test]
+fn tagged_items_are_kept_when_configured() {
+    // room for one item
+    let history = History::new(DaemonConfig {
+        max_text_bytes: Some(4 + std::mem::size_of::<HistoryItem>()),
+        keep_tagged: true,
+        ..Default::default()
+    });
+
+    history::insert_item(&history, "text/plain".into(), b"meow".as_slice().into(), 0).unwrap();
+    assert!(history::set_tag(&history, 0, "cat", true));
+    history::insert_item(&history, "text/plain".into(), b"woof".as_slice().into(), 0).unwrap();
+    history::insert_item(&history, "text/plain".into(), b"purr".as_slice().into(), 0).unwrap();
+
+    let items = history.items.lock().unwrap();
+    assert_eq!(items.len(), 2);
+    assert_eq!(&*items[0].data, b"meow");
+    assert_eq!(&*items[1].data, b"purr");
+}
+
+#[test]
+fn tagged_items_survive_clear_and_delete_range_unless_forced() {
+    let daemon = Daemon::new(DaemonConfig {
+        keep_tagged: true,
+        ..Default::default()
+    });
+
+    for data in [b"1", b"2", b"3", b"4"] {
+        daemon.store("text/plain", data);
+    }
+    let items = daemon.read_items();
+    let id = |data: &[u8]| items.iter().find(|item| &*item.data == data).unwrap().id;
+    daemon.request(&tag_request(
+        clippyboard_shared::MESSAGE_TAG,
+        id(b"1"),
+        "cat",
+    ));
+
+    let mut delete = vec![clippyboard_shared::MESSAGE_DELETE_RANGE];
+    delete.extend_from_slice(&id(b"3").to_le_bytes());
+    delete.push(clippyboard_shared::DELETE_OLDER);
+    let mut deleted = 1u64.to_le_bytes().to_vec();
+    deleted.extend_from_slice(&id(b"2").to_le_bytes());
+    assert_eq!(daemon.request(&delete), deleted);
+    let data = daemon
+        .read_items()
+        .iter()
+        .map(|item| item.data.to_vec())
+        .collect::<Vec<_>>();
+    assert_eq!(data, [b"1", b"3", b"4"]);
+
+    daemon.request(&[
+        clippyboard_shared::MESSAGE_CLEAR,
+        clippyboard_shared::CLEAR_ALL,
+        0,
+    ]);
+    let items = daemon.read_items();
+    assert_eq!(items.len(), 1);
+    assert_eq!(&*items[0].data, b"1");
+
+    daemon.request(&[
+        clippyboard_shared::MESSAGE_CLEAR,
+        clippyboard_shared::CLEAR_ALL,
+        1,
+    ]);
+    assert!(daemon.read_items().is_empty());
+}
+
+#[test]
+fn force_clear_clears_a_kept_current_item() {
+    let daemon = Daemon::new(DaemonConfig {
+        keep_tagged: true,
+        ..Default::default()
+    });
+    daemon.store("text/plain", b"meow");
+    let id = daemon.read_items()[0].id;
+    daemon.request(&tag_request(clippyboard_shared::MESSAGE_TAG, id, "cat"));
+
+    let clear = |force| {
+        daemon.request(&[
+            clippyboard_shared::MESSAGE_CLEAR,
+            clippyboard_shared::CLEAR_ALL,
+            force,
+        ]);
+    };
+    clear(0);
+    assert_eq!(daemon.read_items().len(), 1);
+    assert_eq!(*daemon.clipboard.clear_count.lock().unwrap(), 0);
+
+    clear(1);
+    assert!(daemon.read_items().is_empty());
+    assert_eq!(*daemon.clipboard.clear_count.lock().unwrap(), 1);
+}
+
+fn png(width: u32, height: u32) -> Vec<u8> {
+    let mut png = Vec::new();
+    image::RgbImage::new(width, height)
+        .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+    png
+}
+
+#[test]
+fn images_are_skipped_when_turned_off() {
+    let history = History::new(DaemonConfig {
+        store_images: false,
+        ..Default::default()
+    });
+
+    history::insert_item(&history, "image/png".into(), png(20, 10).into(), 0).unwrap();
+    history::insert_item(&history, "text/plain".into(), b"meow".as_slice().into(), 0).unwrap();
+
+    let items = history.items.lock().unwrap();
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].mime, "text/plain");
+}
+
+#[test]
+fn images_are_transcoded() {
+    let jpeg = history::transcode_image(&png(20, 10), "image/png", "image/jpeg")
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        image::guess_format(&jpeg).unwrap(),
+        image::ImageFormat::Jpeg
+    );
+
+    let png_again = history::transcode_image(&jpeg, "image/jpg", "image/png")
+        .unwrap()
+        .unwrap();
+    let image = image::load_from_memory_with_format(&png_again, image::ImageFormat::Png).unwrap();
+    assert_eq!((image.width(), image.height()), (20, 10));
+
+    assert!(
+        history::transcode_image(&jpeg, "image/jpg", "image/jpeg")
+            .unwrap()
+            .is_none()
+    );
+    assert!(history::transcode_image(b"broken", "image/png", "image/jpeg").is_err());
+}
+
+#[test]
+fn large_images_are_downscaled_when_configured() {
+    let history = History::new(DaemonConfig {
+        max_image_dimension: Some(30),
+        ..Default::default()
+    });
+
+    history::insert_item(&history, "image/png".into(), png(300, 100).into(), 0).unwrap();
+    history::insert_item(&history, "image/png".into(), png(20, 10).into(), 0).unwrap();
+
+    let items = history.items.lock().unwrap();
+    let large = image::load_from_memory(&items[0].data).unwrap();
+    assert_eq!((large.width(), large.height()), (30, 10));
+    assert_eq!(*items[1].data, png(20, 10));
+}
+
+#[test]
+fn thumbnails_are_stored_when_configured() {
+    let history = History::new(DaemonConfig {
+        image_thumbnails: true,
+        ..Default::default()
+    });
+
+    history::insert_item(&history, "image/png".into(), png(1000, 500).into(), 0).unwrap();
+    history::insert_item(&history, "text/plain".into(), b"meow".as_slice().into(), 0).unwrap();
+
+    let items = history.items.lock().unwrap();
+    let thumbnail = image::load_from_memory(items[0].thumbnail.as_ref().unwrap()).unwrap();
+    assert_eq!((thumbnail.width(), thumbnail.height()), (128, 64));
+    assert_eq!(*items[0].data, png(1000, 500));
+    assert_eq!(items[1].thumbnail, None);
+}
+
+#[test]
+fn read_meta_creates_thumbnails() {
+    let daemon = Daemon::new(DaemonConfig::default());
+    history::insert_item(
+        &daemon.history,
+        "image/png".into(),
+        png(1000, 500).into(),
+        0,
+    )
+    .unwrap();
+    history::insert_item(
+        &daemon.history,
+        "image/png".into(),
+        b"broken".as_slice().into(),
+        0,
+    )
+    .unwrap();
+    assert_eq!(daemon.history.items.lock().unwrap()[0].thumbnail, None);
+
+    let mut read_meta = vec![clippyboard_shared::MESSAGE_READ_META];
+    read_meta.extend_from_slice(&10_u64.to_le_bytes());
+    for _ in 0..2 {
+        let response = daemon.request(&read_meta);
+        let items: Vec<HistoryItemMeta> = ciborium::from_reader(response.as_slice()).unwrap();
+        let thumbnail = image::load_from_memory(items[0].thumbnail.as_ref().unwrap()).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (128, 64));
+        assert_eq!(items[1].thumbnail, None);
+    }
+}
+
+#[test]
+fn subscribe_streams_new_items() {
+    let daemon = Daemon::new(DaemonConfig::default());
+    let (mut client, server) = UnixStream::pair().unwrap();
+    let done = std::sync::atomic::AtomicBool::new(false);
+
+    std::thread::scope(|scope| {
+        let handler = scope.spawn(|| peer::handle_peer(server, &daemon.history, &daemon.clipboard));
+        client
+            .write_all(&[PROTOCOL_VERSION, clippyboard_shared::MESSAGE_SUBSCRIBE])
+            .unwrap();
+
+        // Items stored before the handler subscribed are not sent, so keep storing until one is.
+        scope.spawn(|| {
+            for n in 0.. {
+                if done.load(std::sync::atomic::Ordering::Relaxed) {
+                    break;
+                }
+                let data = format!("item {n}").into_bytes();
+                history::insert_item(&daemon.history, "text/plain".into(), data.into(), 0).unwrap();
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        });
+
+        let mut version = [0; 1];
+        client.read_exact(&mut version).unwrap();
+        let frame = clippyboard_shared::read_framed(&client, u64::MAX).unwrap();
+        let item: HistoryItem = ciborium::from_reader(frame.as_slice()).unwrap();
+        assert!(item.data.starts_with(b"item "));
+
+        // The handler notices that we're gone with the next item.
+        drop(client);
+        handler.join().unwrap().unwrap();
+        done.store(true, std::sync::atomic::Ordering::Relaxed);
+    });
+}
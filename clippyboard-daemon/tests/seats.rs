@@ -0,0 +1,36 @@
+use clippyboard_daemon::seats::selection_targets;
+
+#[test]
+fn every_seat_gets_the_selection() {
+    let targets = selection_targets([(3, 0, Some("seat0")), (7, 1, Some("seat1"))]);
+    assert_eq!(targets, [3, 7]);
+}
+
+#[test]
+fn only_the_newest_device_of_a_seat_gets_the_selection() {
+    // seat0 was advertised again as global 9 before the removal of global 3 was handled.
+    let targets = selection_targets([
+        (3, 0, Some("seat0")),
+        (9, 2, Some("seat0")),
+        (7, 1, Some("seat1")),
+    ]);
+    assert_eq!(targets, [7, 9]);
+}
+
+#[test]
+fn the_newest_device_is_told_by_generation_not_global_name() {
+    // The compositor reused a lower global name for the seat advertised again.
+    let targets = selection_targets([(8, 0, Some("seat0")), (2, 1, Some("seat0"))]);
+    assert_eq!(targets, [2]);
+}
+
+#[test]
+fn seats_without_a_name_are_all_used() {
+    let targets = selection_targets([(3, 0, None), (4, 1, None), (5, 2, Some("seat0"))]);
+    assert_eq!(targets, [3, 4, 5]);
+}
+
+#[test]
+fn no_devices_get_no_selection() {
+    assert!(selection_targets([]).is_empty());
+}
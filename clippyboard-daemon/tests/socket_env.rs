@@ -0,0 +1,193 @@
+use clippyboard_daemon::history::{self, History};
+use clippyboard_daemon::peer::{self, Clipboard};
+use clippyboard_shared::HistoryItem;
+use clippyboard_shared::config::DaemonConfig;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+struct NoClipboard;
+
+impl Clipboard for NoClipboard {
+    fn set_selection(&self, _mime: &str, _data: Arc<[u8]>, _transient: bool) -> eyre::Result<()> {
+        Ok(())
+    }
+
+    fn set_primary_selection(&self, _mime: &str, _data: Arc<[u8]>) -> eyre::Result<()> {
+        Ok(())
+    }
+
+    fn clear_selection(&self) -> eyre::Result<()> {
+        Ok(())
+    }
+}
+
+/// The other tools of the workspace, built next to this test by `cargo test --workspace`.
+fn tool(name: &str) -> PathBuf {
+    let dir = std::env::current_exe().unwrap();
+    // target/debug/deps/socket_env-<hash>
+    let path = dir.parent().unwrap().parent().unwrap().join(name);
+    assert!(
+        path.exists(),
+        "{} is missing, run the tests with --workspace",
+        path.display()
+    );
+    path
+}
+
+fn run(name: &str, args: &[&str], socket_path: &Path) {
+    let output = Command::new(tool(name))
+        .args(args)
+        .env("CLIPPYBOARD_SOCKET", socket_path)
+        .env("CLIPPYBOARD_CONFIG", "/dev/null")
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{name} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+// Environment variables are process-global, so everything touching them lives in one test.
+#[test]
+fn all_tools_use_the_socket_from_the_environment() {
+    let dir = std::env::temp_dir().join(format!("clippyboard-test-env-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let socket_path = dir.join("clippyboard.sock");
+    let _ = std::fs::remove_file(&socket_path);
+
+    // SAFETY: no other threads of this test binary read the environment.
+    unsafe {
+        std::env::set_var("CLIPPYBOARD_SOCKET", &socket_path);
+    }
+    // The daemon binds the path from here.
+    assert_eq!(clippyboard_shared::socket_path().path, socket_path);
+
+    let listener = UnixListener::bind(&socket_path).unwrap();
+    let history = Arc::new(History::new(DaemonConfig::default()));
+    let daemon_history = history.clone();
+    std::thread::spawn(move || {
+        // One client at a time, so each tool is handled completely before the next one runs.
+        for peer in listener.incoming() {
+            peer::handle_peer(peer.unwrap(), &daemon_history, &NoClipboard).unwrap();
+        }
+    });
+
+    run("clippyboard-store", &["from the environment"], &socket_path);
+
+    let backup_path = dir.join("backup.cbor");
+    run(
+        "clippyboard-backup",
+        &["dump", backup_path.to_str().unwrap()],
+        &socket_path,
+    );
+    let items: Vec<HistoryItem> =
+        ciborium::from_reader(std::fs::File::open(&backup_path).unwrap()).unwrap();
+    assert_eq!(items.len(), 1);
+    assert_eq!(&*items[0].data, b"from the environment");
+
+    run("clippyboard-clear", &[], &socket_path);
+    // Waits for the answer, so the clear was handled before.
+    run("clippyboard-ping", &[], &socket_path);
+    assert!(history.items.lock().unwrap().is_empty());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Serves `history` on a new socket in `dir`, every client on its own thread, since the sync tool
+/// keeps its subscriptions open. Returns the socket and the number of accepted clients.
+fn serve(dir: &Path, name: &str, history: Arc<History>) -> (PathBuf, Arc<AtomicUsize>) {
+    let socket_path = dir.join(name);
+    let _ = std::fs::remove_file(&socket_path);
+    let listener = UnixListener::bind(&socket_path).unwrap();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let daemon_accepted = accepted.clone();
+    std::thread::spawn(move || {
+        for peer in listener.incoming() {
+            let peer = peer.unwrap();
+            let history = history.clone();
+            daemon_accepted.fetch_add(1, Ordering::SeqCst);
+            std::thread::spawn(move || {
+                let _ = peer::handle_peer(peer, &history, &NoClipboard);
+            });
+        }
+    });
+    (socket_path, accepted)
+}
+
+fn store(history: &History, data: &str) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let created_time = u64::try_from(now.as_millis()).unwrap();
+    history::insert_item(
+        history,
+        "text/plain".to_owned(),
+        data.as_bytes().into(),
+        created_time,
+    )
+    .unwrap();
+}
+
+fn contents(history: &History) -> Vec<String> {
+    let items = history.items.lock().unwrap();
+    items
+        .iter()
+        .map(|item| String::from_utf8(item.data.to_vec()).unwrap())
+        .collect()
+}
+
+fn wait_for(history: &History, expected: &[&str]) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while contents(history) != expected {
+        assert!(
+            Instant::now() < deadline,
+            "history is {:?} instead of {expected:?}",
+            contents(history)
+        );
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn sync_mirrors_entries_without_sending_them_back() {
+    let dir = std::env::temp_dir().join(format!("clippyboard-test-sync-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let local = Arc::new(History::new(DaemonConfig::default()));
+    let remote = Arc::new(History::new(DaemonConfig::default()));
+    let (local_socket, _) = serve(&dir, "local.sock", local.clone());
+    let (remote_socket, remote_accepted) = serve(&dir, "remote.sock", remote.clone());
+
+    let mut sync = Command::new(tool("clippyboard-sync"))
+        .arg("--remote")
+        .arg(&remote_socket)
+        .env("CLIPPYBOARD_SOCKET", &local_socket)
+        .env("CLIPPYBOARD_CONFIG", "/dev/null")
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    // The remote side is connected last, after the local subscription was sent.
+    while remote_accepted.load(Ordering::SeqCst) < 2 {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    std::thread::sleep(Duration::from_millis(200));
+
+    store(&local, "one");
+    wait_for(&remote, &["one"]);
+    store(&remote, "two");
+    wait_for(&local, &["one", "two"]);
+    // Already further back in the history, but copied again.
+    store(&remote, "one");
+    wait_for(&local, &["one", "two", "one"]);
+
+    // Nothing bounces back.
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(contents(&local), ["one", "two", "one"]);
+    assert_eq!(contents(&remote), ["one", "two", "one"]);
+
+    sync.kill().unwrap();
+    sync.wait().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,26 @@
+use clippyboard_shared::HistoryItem;
+use std::process::Command;
+
+/// Needs a compositor supporting ext-data-control-v1 (for example a headless sway) and `wl-copy`.
+#[test]
+#[ignore = "needs a Wayland compositor"]
+fn selection_from_before_startup_is_captured() {
+    let text = format!("copied before startup {}", std::process::id());
+    let status = Command::new("wl-copy").arg(&text).status().unwrap();
+    assert!(status.success());
+
+    let socket_path =
+        std::env::temp_dir().join(format!("clippyboard-test-{}.sock", std::process::id()));
+    let output = Command::new(env!("CARGO_BIN_EXE_clippyboard-daemon"))
+        .args(["--oneshot", "1", "--oneshot-timeout", "5"])
+        .env("CLIPPYBOARD_SOCKET", &socket_path)
+        .env("CLIPPYBOARD_CONFIG", "/dev/null")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let items: Vec<HistoryItem> = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].mime, "text/plain");
+    assert_eq!(&*items[0].data, text.as_bytes());
+}
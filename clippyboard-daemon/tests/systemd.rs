@@ -0,0 +1,33 @@
+use clippyboard_daemon::systemd;
+use std::os::unix::net::UnixDatagram;
+
+// Environment variables are process-global, so everything touching them lives in one test.
+#[test]
+fn notify_ready_and_foreign_listen_fds() {
+    let dir = std::env::temp_dir().join(format!("clippyboard-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let notify_path = dir.join("notify.sock");
+    let _ = std::fs::remove_file(&notify_path);
+    let notify_socket = UnixDatagram::bind(&notify_path).unwrap();
+
+    // SAFETY: no other threads of this test binary read the environment.
+    unsafe {
+        std::env::set_var("NOTIFY_SOCKET", &notify_path);
+        std::env::set_var("LISTEN_PID", (std::process::id() + 1).to_string());
+        std::env::set_var("LISTEN_FDS", "1");
+    }
+
+    systemd::notify_ready().unwrap();
+    let mut buf = [0; 64];
+    let len = notify_socket.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"READY=1");
+
+    // The fds were meant for a different process.
+    // SAFETY: no other threads of this test binary read the environment.
+    assert!(unsafe { systemd::activated_listener() }.unwrap().is_none());
+    // Our children must not see them either.
+    assert!(std::env::var_os("LISTEN_PID").is_none());
+    assert!(std::env::var_os("LISTEN_FDS").is_none());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
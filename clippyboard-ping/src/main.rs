@@ -0,0 +1,32 @@
+const USAGE: &str = "\
+Usage: clippyboard-ping [OPTIONS]
+
+Exits successfully if the daemon is running and answers.
+
+Options:
+  -V, --version    Print the version
+  -h, --help       Print this help
+";
+
+/// Exits successfully if the daemon is running and answers, without reading the history.
+fn main() -> std::process::ExitCode {
+    clippyboard_shared::exit::report(run())
+}
+
+fn run() -> eyre::Result<()> {
+    match std::env::args().nth(1).as_deref() {
+        None => {}
+        Some("--help" | "-h") => {
+            print!("{USAGE}");
+            return Ok(());
+        }
+        Some("--version" | "-V") => {
+            println!("{}", clippyboard_shared::version!());
+            return Ok(());
+        }
+        Some(arg) => eyre::bail!("unknown argument {arg}, see --help"),
+    }
+
+    let socket_path = clippyboard_shared::socket_path().path;
+    clippyboard_shared::ping(&socket_path)
+}
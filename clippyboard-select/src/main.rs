@@ -1,101 +1,883 @@
 use clippyboard_shared::HistoryItem;
+use clippyboard_shared::HistoryItemMeta;
 use clippyboard_shared::MESSAGE_COPY;
-use clippyboard_shared::MESSAGE_READ;
+use clippyboard_shared::MESSAGE_COPY_AS_FILE;
+use clippyboard_shared::MESSAGE_COPY_PRIMARY;
+use clippyboard_shared::MESSAGE_DELETE_RANGE;
+use clippyboard_shared::MESSAGE_GET;
+use clippyboard_shared::MESSAGE_READ_META;
+use clippyboard_shared::MESSAGE_SET_SELECTION;
+use clippyboard_shared::MESSAGE_SUBSCRIBE;
+use clippyboard_shared::MESSAGE_TAG;
+use clippyboard_shared::MESSAGE_UNTAG;
+use clippyboard_shared::config::{Config, SearchMode, Theme};
 use eframe::egui;
 use eyre::Context;
+use fuzzy_matcher::FuzzyMatcher;
+use fuzzy_matcher::skim::SkimMatcherV2;
 use std::{
-    io::{BufReader, Write},
+    cmp::Reverse,
+    collections::HashMap,
+    io::{BufReader, Read, Write},
     os::unix::net::UnixStream,
-    time::Instant,
+    path::Path,
+    sync::{Arc, mpsc},
+    time::{Duration, Instant, SystemTime},
 };
 
+const STATUS_DURATION: Duration = Duration::from_secs(1);
+/// Entries younger than this are in the "Just now" section of the history list.
+const JUST_NOW: Duration = Duration::from_secs(10 * 60);
+/// Pressing the Nth key copies the Nth visible entry.
+const QUICK_SELECT_KEYS: [egui::Key; 9] = [
+    egui::Key::Num1,
+    egui::Key::Num2,
+    egui::Key::Num3,
+    egui::Key::Num4,
+    egui::Key::Num5,
+    egui::Key::Num6,
+    egui::Key::Num7,
+    egui::Key::Num8,
+    egui::Key::Num9,
+];
+
+/// Shown in the help overlay opened with `?`. Keep in sync with the key handling in `App::update`.
+const SHORTCUTS: &[(&str, &str)] = &[
+    ("J / ↓, K / ↑", "Select the next or previous entry"),
+    ("Page Down, Page Up", "Move by a screen"),
+    ("Home, End", "Select the first or last entry"),
+    ("Enter", "Copy the entry and close"),
+    ("Shift+Enter", "Copy the entry and keep the window open"),
+    ("1 – 9", "Copy the entry with that number"),
+    ("Ctrl+Enter", "Copy the text selected in the detail panel"),
+    ("X", "Copy text transformed, like trimmed or lowercased"),
+    ("P", "Copy into the primary selection"),
+    ("F", "Copy an image as a file"),
+    ("I", "Copy the ID of the entry"),
+    (
+        "/",
+        "Search, Tab switches between substring, fuzzy and regex",
+    ),
+    (
+        "Shift+J, Shift+K",
+        "Delete all entries below or above, Y confirms",
+    ),
+    ("T", "Add or remove a tag"),
+    (
+        "G",
+        "Show only entries with a tag, cycling through the tags",
+    ),
+    (
+        "E",
+        "Show only entries from a seat, cycling through the seats",
+    ),
+    ("S", "Sort by copy count or recency"),
+    ("R", "Show the oldest entries first or last"),
+    ("Q", "Show text as a QR code"),
+    ("M", "Show text in a monospace font"),
+    ("C", "Compact list"),
+    ("?", "Show or hide this help"),
+    ("Escape", "Close"),
+];
+
+/// Headers grouping the history list by age, only shown while it's sorted by time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TimeSection {
+    JustNow,
+    EarlierToday,
+    Yesterday,
+    Older,
+}
+
+impl TimeSection {
+    /// `created_time` and the starts of the local days are in milliseconds since the UNIX epoch.
+    fn of(created_time: u64, now: Duration, (today, yesterday): (u64, u64)) -> Self {
+        if now.saturating_sub(Duration::from_millis(created_time)) < JUST_NOW {
+            Self::JustNow
+        } else if created_time >= today {
+            Self::EarlierToday
+        } else if created_time >= yesterday {
+            Self::Yesterday
+        } else {
+            Self::Older
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::JustNow => "Just now",
+            Self::EarlierToday => "Earlier today",
+            Self::Yesterday => "Yesterday",
+            Self::Older => "Older",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SortMode {
+    Recency,
+    Frequency,
+}
+
 pub(crate) struct App {
-    pub(crate) items: Vec<HistoryItem>,
+    /// Most recently used first.
+    pub(crate) items: Vec<HistoryItemMeta>,
+    /// Data of the items that were shown so far by ID, fetched when they are first shown.
+    pub(crate) data: HashMap<u64, Arc<[u8]>>,
+    /// Indices into `items` in the order they are displayed.
+    pub(crate) visible: Vec<usize>,
+    /// Index into `visible`.
     pub(crate) selected_idx: usize,
-    pub(crate) socket: UnixStream,
+    pub(crate) sort_mode: SortMode,
+    /// Show the oldest items at the top instead of the most recent ones.
+    pub(crate) oldest_first: bool,
+    /// Connection to the daemon, used for all requests.
+    pub(crate) connection: UnixStream,
+    /// Items stored by the daemon while the window is open.
+    pub(crate) new_items: mpsc::Receiver<HistoryItem>,
+    /// Transient message shown below the heading, with the time it was set.
+    pub(crate) status: Option<(String, Instant)>,
+    /// The part of the detail text that is currently selected, if any.
+    pub(crate) text_selection: Option<String>,
+    /// Whether the detail text is shown in a monospace font, for code-like content.
+    pub(crate) monospace: bool,
+    /// Whether the history rows are shown as single lines with less padding, to fit more of them.
+    pub(crate) compact: bool,
+    /// How many history rows fit on the screen, measured in the last frame.
+    pub(crate) page_size: usize,
+    /// Outline color of the selected history entry.
+    pub(crate) accent_color: egui::Color32,
+    /// Only show items copied on this seat.
+    pub(crate) seat_filter: Option<String>,
+    /// Only show items with this tag.
+    pub(crate) tag_filter: Option<String>,
+    /// The tag being typed for the selected entry, while the tag editor is open.
+    pub(crate) tag_input: Option<String>,
+    /// Whether the chooser for copying the selected text transformed is open.
+    pub(crate) transform_input: bool,
+    /// The `DELETE_*` direction of a range deletion that waits for confirmation.
+    pub(crate) confirm_delete: Option<u8>,
+    /// Whether the overlay listing the keyboard shortcuts is shown.
+    pub(crate) show_help: bool,
+    /// The query while the search is open. Only text entries are searched, by their preview.
+    pub(crate) search: Option<String>,
+    pub(crate) search_mode: SearchMode,
+    /// Why the query can't be used, like an invalid regex.
+    pub(crate) search_error: Option<String>,
+    /// Whether to show text entries as a QR code in the detail panel.
+    pub(crate) show_qr_code: bool,
+    /// The QR code of the item with this ID, or `None` if it's too long for one.
+    pub(crate) qr_code: Option<(u64, Option<egui::TextureHandle>)>,
+    pub(crate) list_preview_chars: usize,
+    pub(crate) detail_max_chars: usize,
+    pub(crate) close_on_focus_loss: bool,
+    /// Whether the window was focused so far. It only closes on focus loss after that,
+    /// since it may not be focused yet when it opens.
+    pub(crate) had_focus: bool,
+}
+
+impl App {
+    fn selected_item(&self) -> Option<&HistoryItemMeta> {
+        self.visible
+            .get(self.selected_idx)
+            .map(|&idx| &self.items[idx])
+    }
+
+    /// Returns the data of the item, fetching it from the daemon the first time.
+    fn item_data(&mut self, id: u64) -> eyre::Result<Arc<[u8]>> {
+        if let Some(data) = self.data.get(&id) {
+            return Ok(data.clone());
+        }
+        let data: Arc<[u8]> = get_item_data(&self.connection, id)?.into();
+        self.data.insert(id, data.clone());
+        Ok(data)
+    }
+
+    /// Asks for confirmation to delete all items older or newer than the selected one, one of the
+    /// `DELETE_*` directions.
+    fn request_delete_range(&mut self, direction: u8) {
+        if self.sort_mode != SortMode::Recency {
+            self.status = Some((
+                "Deleting entries only works when sorted by recency".to_owned(),
+                Instant::now(),
+            ));
+            return;
+        }
+        if self.selected_item().is_some() {
+            self.confirm_delete = Some(direction);
+        }
+    }
+
+    /// Deletes all items older or newer than the selected one, one of the `DELETE_*` directions.
+    fn delete_range(&mut self, direction: u8) {
+        let Some(selected_id) = self.selected_item().map(|item| item.id) else {
+            return;
+        };
+
+        // The daemon may keep some of them, so only the ones it deleted are removed here.
+        let status = match delete_range(&self.connection, selected_id, direction) {
+            Ok(deleted) => {
+                self.items.retain(|item| !deleted.contains(&item.id));
+                self.rebuild_visible(Some(selected_id));
+                format!("Deleted {} entries", deleted.len())
+            }
+            Err(err) => format!("Failed to delete: {err:#}"),
+        };
+        self.status = Some((status, Instant::now()));
+    }
+
+    /// Adds the tag to the selected item, or removes it if the item already has it.
+    fn toggle_tag(&mut self, tag: &str) {
+        let Some(&selected) = self.visible.get(self.selected_idx) else {
+            return;
+        };
+        let item = &mut self.items[selected];
+        let tagged = !item.tags.iter().any(|existing| existing == tag);
+
+        let status = match set_tag(&self.connection, item.id, tag, tagged) {
+            Ok(()) if tagged => {
+                item.tags.push(tag.to_owned());
+                format!("Tagged with {tag}")
+            }
+            Ok(()) => {
+                item.tags.retain(|existing| existing != tag);
+                format!("Removed tag {tag}")
+            }
+            Err(err) => format!("Failed to change tags: {err:#}"),
+        };
+        self.update_visible();
+        self.status = Some((status, Instant::now()));
+    }
+
+    /// Copies the selected item and exits, or stays open with `keep_open`.
+    fn copy_selected(&mut self, keep_open: bool) {
+        let Some(id) = self.selected_item().map(|item| item.id) else {
+            return;
+        };
+        let result = copy_item(&self.connection, id);
+        if keep_open {
+            let status = match result {
+                Ok(()) => "Copied!".to_owned(),
+                Err(err) => format!("Failed to copy: {err:#}"),
+            };
+            self.status = Some((status, Instant::now()));
+        } else {
+            if let Err(err) = result {
+                eprintln!("ERROR: {err:?}");
+                std::process::exit(1);
+            }
+            std::process::exit(0);
+        }
+    }
+
+    /// Adds the items stored since the last frame and reloads the rest of the history,
+    /// since storing them may have evicted or merged other items.
+    fn receive_new_items(&mut self) {
+        let mut received = false;
+        while let Ok(item) = self.new_items.try_recv() {
+            self.data.insert(item.id, item.data);
+            received = true;
+        }
+        if !received {
+            return;
+        }
+
+        match read_items(&self.connection, self.list_preview_chars) {
+            Ok(items) => {
+                let selected_id = self.selected_item().map(|item| item.id);
+                self.items = items;
+                self.data
+                    .retain(|id, _| self.items.iter().any(|item| item.id == *id));
+                self.rebuild_visible(selected_id);
+            }
+            Err(err) => {
+                self.status = Some((
+                    format!("Failed to read new entries: {err:#}"),
+                    Instant::now(),
+                ));
+            }
+        }
+    }
+
+    /// Rebuilds `visible` for the current sort mode, keeping the selected item selected.
+    fn update_visible(&mut self) {
+        let selected_id = self.selected_item().map(|item| item.id);
+        self.rebuild_visible(selected_id);
+    }
+
+    /// Rebuilds `visible`, selecting the item with `selected_id`. If it's gone, the selection
+    /// stays at the same position.
+    fn rebuild_visible(&mut self, selected_id: Option<u64>) {
+        self.visible = (0..self.items.len())
+            .filter(|&idx| self.seat_filter.is_none() || self.items[idx].seat == self.seat_filter)
+            .filter(|&idx| {
+                self.tag_filter
+                    .as_ref()
+                    .is_none_or(|tag| self.items[idx].tags.contains(tag))
+            })
+            .collect();
+        if self.oldest_first {
+            self.visible.reverse();
+        }
+        if self.sort_mode == SortMode::Frequency {
+            // stable, so items copied equally often stay ordered by recency
+            self.visible
+                .sort_by_key(|&idx| Reverse(self.items[idx].copy_count));
+        }
+        self.search_error = None;
+        if let Some(query) = self.search.as_deref().filter(|query| !query.is_empty()) {
+            match search(&self.items, &self.visible, query, self.search_mode) {
+                Ok(visible) => self.visible = visible,
+                // Not filtering at all, so it's obvious that the query isn't used.
+                Err(err) => self.search_error = Some(err),
+            }
+        }
+
+        self.selected_idx = self
+            .visible
+            .iter()
+            .position(|&idx| Some(self.items[idx].id) == selected_id)
+            .unwrap_or(self.selected_idx.min(self.visible.len().saturating_sub(1)));
+    }
 }
 
 impl eframe::App for App {
     fn update(&mut self, ctx: &egui::Context, _: &mut eframe::Frame) {
+        self.receive_new_items();
+
+        if self.close_on_focus_loss {
+            match ctx.input(|i| i.viewport().focused) {
+                Some(true) => self.had_focus = true,
+                Some(false) if self.had_focus => std::process::exit(0),
+                _ => {}
+            }
+        }
+
+        if let Some((_, time)) = &self.status {
+            if time.elapsed() >= STATUS_DURATION {
+                self.status = None;
+            } else {
+                ctx.request_repaint_after(STATUS_DURATION - time.elapsed());
+            }
+        }
+
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.input(|i| {
-                if i.key_down(egui::Key::Escape) {
-                    std::process::exit(0);
+                // While a tag is typed, the keys go to the tag editor.
+                if let Some(tag) = &self.tag_input {
+                    if i.key_pressed(egui::Key::Escape) {
+                        self.tag_input = None;
+                    } else if i.key_pressed(egui::Key::Enter) {
+                        let tag = tag.trim().to_owned();
+                        self.tag_input = None;
+                        if !tag.is_empty() {
+                            self.toggle_tag(&tag);
+                        }
+                    }
+                    return;
+                }
+
+                // The help only closes, so that trying keys in it doesn't do anything by accident.
+                if self.show_help {
+                    if i.key_pressed(egui::Key::Questionmark) || i.key_pressed(egui::Key::Escape) {
+                        self.show_help = false;
+                    }
+                    return;
+                }
+
+                // Deleting a range needs a Y, any other key cancels it.
+                if let Some(direction) = self.confirm_delete {
+                    if i.key_pressed(egui::Key::Y) {
+                        self.confirm_delete = None;
+                        self.delete_range(direction);
+                    } else if i
+                        .events
+                        .iter()
+                        .any(|event| matches!(event, egui::Event::Key { pressed: true, .. }))
+                    {
+                        self.confirm_delete = None;
+                    }
+                    return;
+                }
+
+                // While choosing a transform, its key copies the transformed text.
+                if self.transform_input {
+                    if i.key_pressed(egui::Key::Escape) {
+                        self.transform_input = false;
+                    }
+                    let transform = Transform::ALL
+                        .into_iter()
+                        .find(|transform| i.key_pressed(transform.key()));
+                    if let Some(transform) = transform
+                        && let Some(id) = self.selected_item().map(|item| item.id)
+                    {
+                        let result = self.item_data(id).and_then(|data| {
+                            let text = String::from_utf8_lossy(&data);
+                            set_selection(&self.connection, &transform.apply(&text))
+                        });
+                        if let Err(err) = result {
+                            eprintln!("ERROR: {err:?}");
+                            std::process::exit(1);
+                        }
+                        std::process::exit(0);
+                    }
+                    return;
                 }
 
-                if i.key_pressed(egui::Key::J) || i.key_pressed(egui::Key::ArrowDown) {
-                    if self.selected_idx + 1 != self.items.len() {
+                // While searching, the keys go to the search field, except for navigating,
+                // copying and switching the search mode.
+                if self.search.is_some() {
+                    if i.key_pressed(egui::Key::Escape) {
+                        self.search = None;
+                        self.update_visible();
+                    } else if i.key_pressed(egui::Key::Tab) {
+                        let (search_mode, status) = match self.search_mode {
+                            SearchMode::Substring => (SearchMode::Fuzzy, "Fuzzy search"),
+                            SearchMode::Fuzzy => (SearchMode::Regex, "Regex search"),
+                            SearchMode::Regex => (SearchMode::Substring, "Substring search"),
+                        };
+                        self.search_mode = search_mode;
+                        self.selected_idx = 0;
+                        self.rebuild_visible(None);
+                        self.status = Some((status.to_owned(), Instant::now()));
+                    } else if i.key_pressed(egui::Key::ArrowDown)
+                        && self.selected_idx + 1 < self.visible.len()
+                    {
                         self.selected_idx += 1;
+                    } else if i.key_pressed(egui::Key::ArrowUp) {
+                        self.selected_idx = self.selected_idx.saturating_sub(1);
+                    } else if i.key_pressed(egui::Key::Enter) {
+                        self.copy_selected(i.modifiers.shift);
                     }
+                    return;
+                }
+
+                if i.key_pressed(egui::Key::Escape) {
+                    std::process::exit(0);
+                }
+
+                if i.key_pressed(egui::Key::Slash) {
+                    self.search = Some(String::new());
+                    self.selected_idx = 0;
+                }
+
+                if i.key_pressed(egui::Key::Questionmark) {
+                    self.show_help = true;
                 }
-                if i.key_pressed(egui::Key::K) || i.key_pressed(egui::Key::ArrowUp) {
+
+                // Shift+J and Shift+K delete everything below and above the selected entry.
+                if i.modifiers.shift && (i.key_pressed(egui::Key::J) || i.key_pressed(egui::Key::K))
+                {
+                    let older_is_below = !self.oldest_first;
+                    let direction = if i.key_pressed(egui::Key::J) == older_is_below {
+                        clippyboard_shared::DELETE_OLDER
+                    } else {
+                        clippyboard_shared::DELETE_NEWER
+                    };
+                    self.request_delete_range(direction);
+                } else if (i.key_pressed(egui::Key::J) || i.key_pressed(egui::Key::ArrowDown))
+                    && self.selected_idx + 1 < self.visible.len()
+                {
+                    self.selected_idx += 1;
+                    self.status = None;
+                }
+                if (i.key_pressed(egui::Key::K) && !i.modifiers.shift)
+                    || i.key_pressed(egui::Key::ArrowUp)
+                {
                     self.selected_idx = self.selected_idx.saturating_sub(1);
+                    self.status = None;
+                }
+
+                let last_idx = self.visible.len().saturating_sub(1);
+                if i.key_pressed(egui::Key::PageDown) {
+                    self.selected_idx = (self.selected_idx + self.page_size).min(last_idx);
+                    self.status = None;
+                }
+                if i.key_pressed(egui::Key::PageUp) {
+                    self.selected_idx = self.selected_idx.saturating_sub(self.page_size);
+                    self.status = None;
+                }
+                if i.key_pressed(egui::Key::Home) {
+                    self.selected_idx = 0;
+                    self.status = None;
+                }
+                if i.key_pressed(egui::Key::End) {
+                    self.selected_idx = last_idx;
+                    self.status = None;
+                }
+
+                if i.key_pressed(egui::Key::S) {
+                    let (sort_mode, status) = match self.sort_mode {
+                        SortMode::Recency => (SortMode::Frequency, "Sorted by copy count"),
+                        SortMode::Frequency => (SortMode::Recency, "Sorted by recency"),
+                    };
+                    self.sort_mode = sort_mode;
+                    self.update_visible();
+                    self.status = Some((status.to_owned(), Instant::now()));
+                }
+
+                if i.key_pressed(egui::Key::R) {
+                    self.oldest_first = !self.oldest_first;
+                    self.update_visible();
+                    let status = if self.oldest_first {
+                        "Showing oldest first"
+                    } else {
+                        "Showing most recent first"
+                    };
+                    self.status = Some((status.to_owned(), Instant::now()));
+                }
+
+                // E cycles through the seats to show items from.
+                if i.key_pressed(egui::Key::E) {
+                    let mut seats = self
+                        .items
+                        .iter()
+                        .filter_map(|item| item.seat.clone())
+                        .collect::<Vec<_>>();
+                    seats.sort();
+                    seats.dedup();
+                    self.seat_filter = match &self.seat_filter {
+                        None => seats.first().cloned(),
+                        Some(current) => seats
+                            .iter()
+                            .skip_while(|seat| *seat != current)
+                            .nth(1)
+                            .cloned(),
+                    };
+                    self.update_visible();
+                    let status = match &self.seat_filter {
+                        Some(seat) => format!("Showing seat {seat}"),
+                        None => "Showing all seats".to_owned(),
+                    };
+                    self.status = Some((status, Instant::now()));
+                }
+
+                // T opens the tag editor, G cycles through the tags to show items with.
+                if i.key_pressed(egui::Key::T) && self.selected_item().is_some() {
+                    self.tag_input = Some(String::new());
+                }
+                // X copies the text transformed, without storing the result as a new entry.
+                if i.key_pressed(egui::Key::X) {
+                    match self.selected_item() {
+                        Some(item) if item.mime == "text/plain" => self.transform_input = true,
+                        _ => {
+                            self.status = Some(("Not a text entry".to_owned(), Instant::now()));
+                        }
+                    }
+                }
+                if i.key_pressed(egui::Key::G) {
+                    let mut tags = self
+                        .items
+                        .iter()
+                        .flat_map(|item| item.tags.iter().cloned())
+                        .collect::<Vec<_>>();
+                    tags.sort();
+                    tags.dedup();
+                    self.tag_filter = match &self.tag_filter {
+                        None => tags.first().cloned(),
+                        Some(current) => tags
+                            .iter()
+                            .skip_while(|tag| *tag != current)
+                            .nth(1)
+                            .cloned(),
+                    };
+                    self.update_visible();
+                    let status = match &self.tag_filter {
+                        Some(tag) => format!("Showing tag {tag}"),
+                        None => "Showing all tags".to_owned(),
+                    };
+                    self.status = Some((status, Instant::now()));
+                }
+
+                if i.key_pressed(egui::Key::Q) {
+                    self.show_qr_code = !self.show_qr_code;
+                }
+
+                if i.key_pressed(egui::Key::M) {
+                    self.monospace = !self.monospace;
+                }
+
+                if i.key_pressed(egui::Key::C) {
+                    self.compact = !self.compact;
                 }
 
-                if i.key_pressed(egui::Key::Enter)
-                    && let Some(item) = self.items.get(self.selected_idx)
+                for (n, &key) in QUICK_SELECT_KEYS.iter().enumerate() {
+                    if i.key_pressed(key)
+                        && let Some(&item_idx) = self.visible.get(n)
+                    {
+                        if let Err(err) = copy_item(&self.connection, self.items[item_idx].id) {
+                            eprintln!("ERROR: {err:?}");
+                            std::process::exit(1);
+                        }
+                        std::process::exit(0);
+                    }
+                }
+
+                // F copies an image as a file, for apps that only accept pasting files.
+                if i.key_pressed(egui::Key::F)
+                    && let Some(item) = self.selected_item()
+                    && item.mime.starts_with("image/")
+                {
+                    if let Err(err) = copy_item_as_file(&self.connection, item.id) {
+                        eprintln!("ERROR: {err:?}");
+                        std::process::exit(1);
+                    }
+                    std::process::exit(0);
+                }
+
+                // I copies the ID of the entry, for referring to it in scripts.
+                if i.key_pressed(egui::Key::I)
+                    && let Some(item) = self.selected_item()
                 {
-                    let _ = self.socket.write_all(&[MESSAGE_COPY]);
-                    let _ = self.socket.write_all(&item.id.to_le_bytes());
+                    eprintln!("INFO: entry {} has MIME type {}", item.id, item.mime);
+                    let status = match set_selection(&self.connection, &item.id.to_string()) {
+                        Ok(()) => format!("Copied ID {}", item.id),
+                        Err(err) => format!("Failed to copy ID: {err:#}"),
+                    };
+                    self.status = Some((status, Instant::now()));
+                }
+
+                // P copies into the primary selection, for pasting with the middle mouse button.
+                if i.key_pressed(egui::Key::P)
+                    && let Some(item) = self.selected_item()
+                {
+                    if let Err(err) = copy_item_to_primary(&self.connection, item.id) {
+                        eprintln!("ERROR: {err:?}");
+                        std::process::exit(1);
+                    }
                     std::process::exit(0);
                 }
+
+                // Ctrl+Enter copies only the text selected in the detail panel.
+                if i.key_pressed(egui::Key::Enter) && i.modifiers.ctrl {
+                    match &self.text_selection {
+                        Some(selection) => {
+                            if let Err(err) = set_selection(&self.connection, selection) {
+                                eprintln!("ERROR: {err:?}");
+                                std::process::exit(1);
+                            }
+                            std::process::exit(0);
+                        }
+                        None => {
+                            self.status = Some(("No text selected".to_owned(), Instant::now()));
+                        }
+                    }
+                }
+                // Shift+Enter copies but keeps the window open to copy more entries.
+                else if i.key_pressed(egui::Key::Enter) {
+                    self.copy_selected(i.modifiers.shift);
+                }
             });
 
-            ui.heading("clippyboard");
+            ui.horizontal(|ui| {
+                ui.heading("clippyboard");
+                ui.weak("Press ? for the keyboard shortcuts");
+            });
+            if let Some((status, _)) = &self.status {
+                ui.label(status);
+            }
 
             egui::SidePanel::left("selection_panel")
                 .default_width(400.0)
                 .show_inside(ui, |ui| {
                     ui.heading("History");
 
+                    if let Some(query) = &mut self.search {
+                        let response = ui.add(
+                            egui::TextEdit::singleline(query)
+                                .hint_text("Search")
+                                .desired_width(f32::INFINITY),
+                        );
+                        response.request_focus();
+                        if response.changed() {
+                            // the best match
+                            self.selected_idx = 0;
+                            self.rebuild_visible(None);
+                        }
+                    }
+                    if let Some(error) = &self.search_error {
+                        ui.colored_label(ui.visuals().error_fg_color, error);
+                    }
+
                     ui.add_space(10.0);
 
-                    for (idx, item) in self.items.iter().enumerate() {
-                        let mut frame = egui::Frame::new().inner_margin(3.0);
+                    // Fuzzy matches are ordered by how well they match, so the sections would be all over the place.
+                    let show_sections = self.sort_mode == SortMode::Recency
+                        && !(self.search.is_some() && self.search_mode == SearchMode::Fuzzy);
+                    let now = SystemTime::now()
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap();
+                    // Without the local time there is no telling today and yesterday apart.
+                    let day_starts = if show_sections {
+                        local_day_starts(now)
+                    } else {
+                        None
+                    };
+                    let mut section = None;
+
+                    let mut rows_on_screen = 0;
+                    for (idx, &item_idx) in self.visible.iter().enumerate() {
+                        let item = &self.items[item_idx];
+                        let item_section = day_starts
+                            .map(|day_starts| TimeSection::of(item.created_time, now, day_starts));
+                        // The headers are only labels between the entries, `selected_idx` doesn't count them.
+                        if let Some(item_section) = item_section
+                            && section != Some(item_section)
+                        {
+                            section = Some(item_section);
+                            ui.label(egui::RichText::new(item_section.label()).small().strong());
+                        }
+                        // egui only repaints on input, so wake up once when the entry moves out of "Just now".
+                        if item_section == Some(TimeSection::JustNow) {
+                            let age = now.saturating_sub(Duration::from_millis(item.created_time));
+                            ui.ctx().request_repaint_after(JUST_NOW.saturating_sub(age));
+                        }
+                        let margin = if self.compact { 1.0 } else { 3.0 };
+                        let mut frame = egui::Frame::new().inner_margin(margin);
                         if self.selected_idx == idx {
-                            frame = frame.stroke(egui::Stroke::new(1.0, egui::Color32::PURPLE));
+                            frame = frame.stroke(egui::Stroke::new(1.0, self.accent_color));
                         }
-                        frame.show(ui, |ui| match item.mime.as_str() {
-                            "text/plain" => {
-                                let mut full =
-                                    str::from_utf8(&item.data).unwrap_or("<invalid UTF-8>");
-                                if full.len() > 1000 {
-                                    full = &full[..1000];
+                        let response = frame.show(ui, |ui| {
+                            ui.horizontal_top(|ui| {
+                                if idx < QUICK_SELECT_KEYS.len() {
+                                    ui.weak(format!("{}", idx + 1));
                                 }
-                                ui.label(full);
-                            }
-                            "image/png" => {
-                                ui.label("<image>");
-                            }
-                            _ => {
-                                ui.label("<unsupported mime type>");
-                            }
+                                ui.vertical(|ui| {
+                                    show_history_row(ui, item, self.compact);
+                                });
+                            });
                         });
+                        if ui.clip_rect().contains_rect(response.response.rect) {
+                            rows_on_screen += 1;
+                        }
 
-                        ui.separator();
+                        if !self.compact {
+                            ui.separator();
+                        }
                     }
+                    self.page_size = rows_on_screen.max(1);
                 });
 
             egui::CentralPanel::default().show_inside(ui, |ui| {
                 ui.heading("Detail");
-                let Some(item) = &self.items.get(self.selected_idx) else {
+                self.text_selection = None;
+                let Some(&item_idx) = self.visible.get(self.selected_idx) else {
                     return;
                 };
+                let data = self.item_data(self.items[item_idx].id);
+                let item = &self.items[item_idx];
 
                 ui.add_space(10.0);
 
                 ui.label(format!("MIME type: {}", item.mime));
+                ui.label(format!("Size: {}", format_byte_size(item.size as usize)));
+                let data = match data {
+                    Ok(data) => data,
+                    Err(err) => {
+                        ui.label(format!("Failed to load entry: {err:#}"));
+                        return;
+                    }
+                };
+                if item.mime == "text/plain"
+                    && let Ok(text) = str::from_utf8(&data)
+                {
+                    ui.label(format!(
+                        "Characters: {}, lines: {}",
+                        text.chars().count(),
+                        text.lines().count()
+                    ));
+                }
+                ui.label(format!("Captured: {}", format_age(item.created_time)));
+                // Wake up once when the shown age changes, not periodically.
+                ui.ctx()
+                    .request_repaint_after(until_age_changes(item.created_time));
+                if let Some(seat) = &item.seat {
+                    ui.label(format!("Seat: {seat}"));
+                }
+                if let Some(source) = &item.source {
+                    ui.label(format!("Copied from: {source}"));
+                }
+                ui.label(format!("Times copied: {}", item.copy_count));
+                if !item.tags.is_empty() {
+                    ui.label(format!("Tags: {}", item.tags.join(", ")));
+                }
+                if let Some(tag) = &mut self.tag_input {
+                    ui.horizontal(|ui| {
+                        ui.label("Add or remove tag:");
+                        ui.text_edit_singleline(tag).request_focus();
+                    });
+                }
+                if let Some(direction) = self.confirm_delete {
+                    let entries = if direction == clippyboard_shared::DELETE_OLDER {
+                        "older"
+                    } else {
+                        "newer"
+                    };
+                    ui.label(format!(
+                        "Delete all {entries} entries? Y to confirm, any other key cancels"
+                    ));
+                }
+                if self.transform_input {
+                    let choices = Transform::ALL
+                        .iter()
+                        .map(|transform| format!("{:?} {}", transform.key(), transform.name()))
+                        .collect::<Vec<_>>();
+                    ui.label(format!("Copy transformed: {}", choices.join(", ")));
+                }
 
                 ui.add_space(10.0);
 
                 match item.mime.as_str() {
                     "text/plain" => {
-                        ui.label(str::from_utf8(&item.data).unwrap_or("<invalid UTF-8>"));
+                        let mut text = str::from_utf8(&data).unwrap_or("<invalid UTF-8>");
+                        if self.show_qr_code {
+                            if self.qr_code.as_ref().is_none_or(|(id, _)| *id != item.id) {
+                                self.qr_code = Some((item.id, qr_code_texture(ui.ctx(), text)));
+                            }
+                            match &self.qr_code {
+                                Some((_, Some(texture))) => {
+                                    ui.image((texture.id(), egui::vec2(200.0, 200.0)));
+                                }
+                                _ => {
+                                    ui.weak("Too long for a QR code");
+                                }
+                            }
+                        }
+
+                        let full_len = text.len();
+                        text = truncate_chars(text, self.detail_max_chars);
+                        if text.len() < full_len {
+                            ui.weak(format!("… ({} more bytes)", full_len - text.len()));
+                        }
+
+                        let font = if self.monospace {
+                            egui::TextStyle::Monospace
+                        } else {
+                            egui::TextStyle::Body
+                        };
+                        // A text edit instead of a label so that parts of the text can be selected.
+                        // It wraps at the panel width, breaking long lines like URLs anywhere.
+                        let output = egui::ScrollArea::vertical()
+                            .show(ui, |ui| {
+                                egui::TextEdit::multiline(&mut text)
+                                    .id_salt(item.id)
+                                    .font(font)
+                                    .desired_width(f32::INFINITY)
+                                    .show(ui)
+                            })
+                            .inner;
+                        self.text_selection = output
+                            .cursor_range
+                            .filter(|range| !range.is_empty())
+                            .map(|range| {
+                                let range = range.as_sorted_char_range();
+                                text.chars().skip(range.start).take(range.len()).collect()
+                            });
                     }
                     "image/png" => {
                         ui.image(egui::ImageSource::Bytes {
                             uri: format!("bytes://{}", item.id).into(),
-                            bytes: item.data.clone().into(),
+                            bytes: data.into(),
                         });
                     }
                     _ => {
@@ -104,45 +886,481 @@ impl eframe::App for App {
                 }
             });
         });
+
+        if self.show_help {
+            egui::Window::new("Keyboard shortcuts")
+                .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    egui::Grid::new("shortcuts").striped(true).show(ui, |ui| {
+                        for (keys, action) in SHORTCUTS {
+                            ui.strong(*keys);
+                            ui.label(*action);
+                            ui.end_row();
+                        }
+                    });
+                });
+        }
     }
 }
 
-pub fn main() -> eyre::Result<()> {
-    let socket_path = clippyboard_shared::socket_path()?;
+fn show_history_row(ui: &mut egui::Ui, item: &HistoryItemMeta, compact: bool) {
+    match item.mime.as_str() {
+        "text/plain" => {
+            // the daemon already cut it to the configured length
+            let preview = item.preview.as_deref().unwrap_or_default();
+            if compact {
+                let mut lines = preview.trim().lines();
+                let first = lines.next().unwrap_or_default();
+                let text = if lines.next().is_some() || (preview.len() as u64) < item.size {
+                    format!("{first}…")
+                } else {
+                    first.to_owned()
+                };
+                ui.add(egui::Label::new(text).truncate());
+            } else if (preview.len() as u64) < item.size {
+                ui.label(format!("{preview}…"));
+            } else {
+                ui.label(preview);
+            }
+        }
+        mime if mime.starts_with("image/") => match &item.thumbnail {
+            Some(thumbnail) => {
+                ui.add(
+                    egui::Image::from_bytes(
+                        format!("bytes://thumbnail-{}", item.id),
+                        thumbnail.clone(),
+                    )
+                    .max_height(if compact { 20.0 } else { 64.0 }),
+                );
+            }
+            None => {
+                ui.label("<image>");
+            }
+        },
+        _ => {
+            ui.label("<unsupported mime type>");
+        }
+    }
+    // The detail panel still shows these.
+    if compact {
+        return;
+    }
+    if item.copy_count > 0 {
+        ui.weak(format!("copied {}×", item.copy_count));
+    }
+    if !item.tags.is_empty() {
+        ui.weak(item.tags.join(", "));
+    }
+}
+
+/// Returns the entries of `visible`, indices into `items`, whose preview matches `query`, best
+/// matches first, or the error of an invalid regex.
+fn search(
+    items: &[HistoryItemMeta],
+    visible: &[usize],
+    query: &str,
+    mode: SearchMode,
+) -> Result<Vec<usize>, String> {
+    let matcher = SkimMatcherV2::default();
+    let regex = match mode {
+        SearchMode::Regex => Some(regex::Regex::new(query).map_err(|err| err.to_string())?),
+        _ => None,
+    };
+    let lowercase_query = query.to_lowercase();
+
+    let mut scored = visible
+        .iter()
+        .filter_map(|&idx| {
+            let text = items[idx].preview.as_deref()?;
+            let score = match (mode, &regex) {
+                (SearchMode::Fuzzy, _) => matcher.fuzzy_match(text, query)?,
+                (_, Some(regex)) => regex.is_match(text).then_some(0)?,
+                _ => text
+                    .to_lowercase()
+                    .contains(&lowercase_query)
+                    .then_some(0)?,
+            };
+            Some((idx, score))
+        })
+        .collect::<Vec<_>>();
+    // stable, so equally good matches keep their order
+    scored.sort_by_key(|&(_, score)| Reverse(score));
+    Ok(scored.into_iter().map(|(idx, _)| idx).collect())
+}
 
-    let mut socket = UnixStream::connect(&socket_path).wrap_err_with(|| {
-        format!(
-            "connecting to socket at {}. is the daemon running?",
-            socket_path.display()
-        )
-    })?;
+/// Returns the first `max_chars` characters of `text`.
+fn truncate_chars(text: &str, max_chars: usize) -> &str {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    }
+}
+
+/// Texts longer than this are not shown as QR codes, they would be too dense to scan.
+const QR_CODE_MAX_BYTES: usize = 1000;
+
+fn qr_code_texture(ctx: &egui::Context, text: &str) -> Option<egui::TextureHandle> {
+    if text.len() > QR_CODE_MAX_BYTES {
+        return None;
+    }
+    let code = qrcode::QrCode::new(text).ok()?;
+
+    // with the quiet zone of 4 modules around it that scanners need
+    let size = code.width() + 8;
+    let mut image = egui::ColorImage::filled([size, size], egui::Color32::WHITE);
+    for (idx, color) in code.to_colors().into_iter().enumerate() {
+        if color == qrcode::Color::Dark {
+            let (x, y) = (idx % code.width() + 4, idx / code.width() + 4);
+            image[(x, y)] = egui::Color32::BLACK;
+        }
+    }
+
+    // nearest, to keep the modules sharp when scaling up
+    Some(ctx.load_texture("qr-code", image, egui::TextureOptions::NEAREST))
+}
+
+fn copy_item(mut socket: &UnixStream, id: u64) -> eyre::Result<()> {
+    socket
+        .write_all(&[MESSAGE_COPY])
+        .wrap_err("writing request type")?;
+    socket.write_all(&id.to_le_bytes()).wrap_err("writing id")?;
+    Ok(())
+}
+
+fn copy_item_to_primary(mut socket: &UnixStream, id: u64) -> eyre::Result<()> {
+    socket
+        .write_all(&[MESSAGE_COPY_PRIMARY])
+        .wrap_err("writing request type")?;
+    socket.write_all(&id.to_le_bytes()).wrap_err("writing id")?;
+    Ok(())
+}
+
+fn copy_item_as_file(mut socket: &UnixStream, id: u64) -> eyre::Result<()> {
     socket
-        .write_all(&[MESSAGE_READ])
+        .write_all(&[MESSAGE_COPY_AS_FILE])
         .wrap_err("writing request type")?;
+    socket.write_all(&id.to_le_bytes()).wrap_err("writing id")?;
+    Ok(())
+}
+
+/// Reads the metadata of all items, most recent first.
+fn read_items(mut socket: &UnixStream, preview_chars: usize) -> eyre::Result<Vec<HistoryItemMeta>> {
+    socket
+        .write_all(&[MESSAGE_READ_META])
+        .wrap_err("writing request type")?;
+    socket
+        .write_all(&(preview_chars as u64).to_le_bytes())
+        .wrap_err("writing preview length")?;
+    // Entries are decoded one by one, so that a single broken entry doesn't make the whole history unusable.
+    let values: Vec<ciborium::Value> =
+        ciborium::from_reader(BufReader::new(socket)).wrap_err("reading items from socket")?;
+    let mut items = values
+        .into_iter()
+        .filter_map(|value| match value.deserialized::<HistoryItemMeta>() {
+            Ok(item) => Some(item),
+            Err(err) => {
+                eprintln!("WARN: skipping an entry that could not be decoded: {err}");
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    items.reverse();
+    Ok(items)
+}
+
+/// Sends every item that the daemon stores from now on, until the window is closed.
+fn subscribe(
+    socket_path: &Path,
+    ctx: egui::Context,
+    send: mpsc::Sender<HistoryItem>,
+) -> eyre::Result<()> {
+    let mut socket = clippyboard_shared::connect(socket_path)?;
+    socket
+        .write_all(&[MESSAGE_SUBSCRIBE])
+        .wrap_err("writing request type")?;
+
+    std::thread::spawn(move || {
+        while let Ok(frame) = clippyboard_shared::read_framed(&socket, u64::MAX) {
+            // Every item is in its own frame, so a broken one can be skipped.
+            let item = match ciborium::from_reader(frame.as_slice()) {
+                Ok(item) => item,
+                Err(err) => {
+                    eprintln!("WARN: skipping a new entry that could not be decoded: {err}");
+                    continue;
+                }
+            };
+            if send.send(item).is_err() {
+                break;
+            }
+            ctx.request_repaint();
+        }
+    });
+    Ok(())
+}
+
+fn get_item_data(mut socket: &UnixStream, id: u64) -> eyre::Result<Vec<u8>> {
+    socket
+        .write_all(&[MESSAGE_GET])
+        .wrap_err("writing request type")?;
+    socket.write_all(&id.to_le_bytes()).wrap_err("writing id")?;
+
+    let mut exists = [0; 1];
+    socket
+        .read_exact(&mut exists)
+        .wrap_err("reading response")?;
+    if exists[0] == 0 {
+        eyre::bail!("the entry no longer exists");
+    }
+    clippyboard_shared::read_framed(socket, u64::MAX).wrap_err("reading data")
+}
+
+/// Returns the IDs of the deleted items.
+fn delete_range(mut socket: &UnixStream, id: u64, direction: u8) -> eyre::Result<Vec<u64>> {
+    socket
+        .write_all(&[MESSAGE_DELETE_RANGE])
+        .wrap_err("writing request type")?;
+    socket.write_all(&id.to_le_bytes()).wrap_err("writing id")?;
+    socket
+        .write_all(&[direction])
+        .wrap_err("writing direction")?;
+
+    let mut id = [0; 8];
+    socket.read_exact(&mut id).wrap_err("reading response")?;
+    (0..u64::from_le_bytes(id))
+        .map(|_| {
+            socket.read_exact(&mut id).wrap_err("reading response")?;
+            Ok(u64::from_le_bytes(id))
+        })
+        .collect()
+}
+
+fn set_tag(mut socket: &UnixStream, id: u64, tag: &str, tagged: bool) -> eyre::Result<()> {
+    let message = if tagged { MESSAGE_TAG } else { MESSAGE_UNTAG };
+    socket
+        .write_all(&[message])
+        .wrap_err("writing request type")?;
+    socket.write_all(&id.to_le_bytes()).wrap_err("writing id")?;
+    clippyboard_shared::write_framed(&mut socket, tag.as_bytes()).wrap_err("writing tag")?;
+    Ok(())
+}
+
+/// A change to text entries that can be applied when copying them.
+#[derive(Clone, Copy)]
+enum Transform {
+    Trim,
+    Lowercase,
+    Uppercase,
+    /// Joins the lines into one, like for pasting a wrapped paragraph or command.
+    CollapseNewlines,
+}
+
+impl Transform {
+    const ALL: [Transform; 4] = [
+        Transform::Trim,
+        Transform::Lowercase,
+        Transform::Uppercase,
+        Transform::CollapseNewlines,
+    ];
+
+    fn key(self) -> egui::Key {
+        match self {
+            Transform::Trim => egui::Key::T,
+            Transform::Lowercase => egui::Key::L,
+            Transform::Uppercase => egui::Key::U,
+            Transform::CollapseNewlines => egui::Key::N,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Transform::Trim => "trim",
+            Transform::Lowercase => "lowercase",
+            Transform::Uppercase => "uppercase",
+            Transform::CollapseNewlines => "collapse newlines",
+        }
+    }
+
+    fn apply(self, text: &str) -> String {
+        match self {
+            Transform::Trim => text.trim().to_owned(),
+            Transform::Lowercase => text.to_lowercase(),
+            Transform::Uppercase => text.to_uppercase(),
+            Transform::CollapseNewlines => text
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .collect::<Vec<_>>()
+                .join(" "),
+        }
+    }
+}
+
+/// Puts `text` on the clipboard without adding it to the history.
+fn set_selection(mut socket: &UnixStream, text: &str) -> eyre::Result<()> {
+    socket
+        .write_all(&[MESSAGE_SET_SELECTION])
+        .wrap_err("writing request type")?;
+    clippyboard_shared::write_framed(&mut socket, b"text/plain").wrap_err("writing mime type")?;
+    clippyboard_shared::write_framed(&mut socket, text.as_bytes()).wrap_err("writing data")?;
+    Ok(())
+}
+
+fn format_byte_size(size: usize) -> String {
+    const UNITS: &[&str] = &["KB", "MB", "GB"];
+
+    if size < 1000 {
+        return format!("{size} B");
+    }
+    let mut size = size as f64 / 1000.0;
+    let mut unit = UNITS[0];
+    for next_unit in &UNITS[1..] {
+        if size < 1000.0 {
+            break;
+        }
+        size /= 1000.0;
+        unit = next_unit;
+    }
+    format!("{size:.1} {unit}")
+}
+
+/// `created_time` is in milliseconds since the UNIX epoch.
+fn format_age(created_time: u64) -> String {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap();
+    let age = now
+        .saturating_sub(Duration::from_millis(created_time))
+        .as_secs();
+
+    match age {
+        0..60 => "just now".to_owned(),
+        60..3600 => format!("{} min ago", age / 60),
+        3600..86400 => format!("{} h ago", age / 3600),
+        _ => format!("{} days ago", age / 86400),
+    }
+}
+
+/// How long until [`format_age`] shows something else for `created_time`.
+fn until_age_changes(created_time: u64) -> Duration {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap();
+    let age = now.saturating_sub(Duration::from_millis(created_time));
+    let unit = match age.as_secs() {
+        0..3600 => 60,
+        3600..86400 => 3600,
+        _ => 86400,
+    };
+    Duration::from_secs((age.as_secs() / unit + 1) * unit) - age
+}
+
+/// Returns the starts of the local days of `now` and the one before in milliseconds since the
+/// UNIX epoch, or `None` if the local time can't be determined.
+fn local_day_starts(now: Duration) -> Option<(u64, u64)> {
+    let secs = now.as_secs() as libc::time_t;
+    // SAFETY: tm only contains integers and a pointer, for which zero is valid.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // SAFETY: both pointers come from references, localtime_r doesn't keep them.
+    if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+        eprintln!("WARN: failed to get the local time, not grouping the history by day");
+        return None;
+    }
+    // mktime does the calendar and DST math, so days with 23 or 25 hours are handled.
+    let midnight = |mday| {
+        let mut tm = libc::tm {
+            tm_mday: mday,
+            tm_hour: 0,
+            tm_min: 0,
+            tm_sec: 0,
+            tm_isdst: -1,
+            ..tm
+        };
+        // SAFETY: the pointer comes from a reference, mktime doesn't keep it.
+        let start = unsafe { libc::mktime(&mut tm) };
+        if start == -1 {
+            eprintln!(
+                "WARN: failed to get the start of the local day, not grouping the history by day"
+            );
+            return None;
+        }
+        Some(u64::try_from(start).ok()? * 1000)
+    };
+    Some((midnight(tm.tm_mday)?, midnight(tm.tm_mday - 1)?))
+}
+
+const USAGE: &str = "\
+Usage: clippyboard-select [OPTIONS]
+
+Opens a window to pick an entry of the clipboard history to copy.
+
+Options:
+      --autostart  Start the daemon if it isn't running
+  -V, --version    Print the version
+  -h, --help       Print this help
+";
+
+fn main() -> std::process::ExitCode {
+    clippyboard_shared::exit::report(run())
+}
+
+fn run() -> eyre::Result<()> {
+    let mut autostart = false;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--autostart" => autostart = true,
+            "--help" | "-h" => {
+                print!("{USAGE}");
+                return Ok(());
+            }
+            "--version" | "-V" => {
+                println!("{}", clippyboard_shared::version!());
+                return Ok(());
+            }
+            _ => eyre::bail!("unknown argument {arg}, see --help"),
+        }
+    }
+
+    let socket_path = clippyboard_shared::socket_path().path;
+    let config = Config::load().wrap_err("loading config")?.select;
+    let accent_color = match &config.accent_color {
+        Some(color) => egui::Color32::from_hex(color)
+            .map_err(|err| eyre::eyre!("{err:?}"))
+            .wrap_err_with(|| format!("invalid accent_color {color}"))?,
+        None => egui::Color32::PURPLE,
+    };
+    let ui_scale = match std::env::var("CLIPPYBOARD_UI_SCALE") {
+        Ok(scale) => scale
+            .parse()
+            .wrap_err_with(|| format!("invalid CLIPPYBOARD_UI_SCALE {scale}"))?,
+        Err(_) => config.ui_scale,
+    };
+    if !(ui_scale > 0.0 && ui_scale.is_finite()) {
+        eyre::bail!("the UI scale must be a positive number, got {ui_scale}");
+    }
+
+    let connection = if autostart {
+        clippyboard_shared::connect_or_start_daemon(&socket_path)?
+    } else {
+        clippyboard_shared::connect(&socket_path)?
+    };
 
     println!("INFO: Reading clipboard history from socket");
     let start = Instant::now();
-    let mut items: Vec<HistoryItem> =
-        ciborium::from_reader(BufReader::new(socket)).wrap_err("reading items from socket")?;
+    let items = read_items(&connection, config.list_preview_chars)?;
     println!(
         "INFO: Read clipboard history from socket in {:?}",
         start.elapsed()
     );
 
-    items.reverse();
-
-    // heh. good design.
-    let socket = UnixStream::connect(&socket_path).wrap_err_with(|| {
-        format!(
-            "connecting to socket at {}. is the daemon running?",
-            socket_path.display()
-        )
-    })?;
-
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
-            .with_inner_size([500.0, 500.0])
+            .with_inner_size(config.window_size.unwrap_or([500.0, 500.0]))
             .with_app_id("clippyboard"),
+        // A configured size wins over the remembered one.
+        persist_window: config.window_size.is_none(),
+        centered: config.centered,
         ..Default::default()
     };
     eframe::run_native(
@@ -150,11 +1368,50 @@ pub fn main() -> eyre::Result<()> {
         options,
         Box::new(|cc| {
             egui_extras::install_image_loaders(&cc.egui_ctx);
-            Ok(Box::new(App {
+            cc.egui_ctx.set_zoom_factor(ui_scale);
+            cc.egui_ctx.set_theme(match config.theme {
+                Theme::System => egui::ThemePreference::System,
+                Theme::Light => egui::ThemePreference::Light,
+                Theme::Dark => egui::ThemePreference::Dark,
+            });
+            let (send, new_items) = mpsc::channel();
+            if let Err(err) = subscribe(&socket_path, cc.egui_ctx.clone(), send) {
+                eprintln!("WARN: new entries won't show up while the window is open: {err:?}");
+            }
+
+            let mut app = App {
                 items,
+                data: HashMap::new(),
+                visible: Vec::new(),
                 selected_idx: 0,
-                socket,
-            }))
+                sort_mode: SortMode::Recency,
+                oldest_first: config.oldest_first,
+                connection,
+                new_items,
+                status: None,
+                text_selection: None,
+                monospace: false,
+                compact: config.compact,
+                page_size: 1,
+                accent_color,
+                seat_filter: None,
+                tag_filter: None,
+                tag_input: None,
+                transform_input: false,
+                confirm_delete: None,
+                show_help: false,
+                search: None,
+                search_mode: config.search_mode,
+                search_error: None,
+                show_qr_code: false,
+                qr_code: None,
+                list_preview_chars: config.list_preview_chars,
+                detail_max_chars: config.detail_max_chars,
+                close_on_focus_loss: config.close_on_focus_loss,
+                had_focus: false,
+            };
+            app.update_visible();
+            Ok(Box::new(app))
         }),
     )
     .map_err(|err| eyre::eyre!(err.to_string()))
@@ -162,3 +1419,167 @@ pub fn main() -> eyre::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(preview: Option<&str>) -> HistoryItemMeta {
+        HistoryItemMeta {
+            id: 0,
+            mime: "text/plain".to_owned(),
+            size: 0,
+            created_time: 0,
+            hash: 0,
+            copy_count: 0,
+            seat: None,
+            preview: preview.map(str::to_owned),
+            tags: Vec::new(),
+            thumbnail: None,
+            source: None,
+        }
+    }
+
+    #[test]
+    fn truncate_chars_counts_characters_not_bytes() {
+        assert_eq!(truncate_chars("grüße", 3), "grü");
+        assert_eq!(truncate_chars("🦀🦀🦀", 2), "🦀🦀");
+        assert_eq!(truncate_chars("grüße", 5), "grüße");
+        assert_eq!(truncate_chars("grüße", 10), "grüße");
+        assert_eq!(truncate_chars("grüße", 0), "");
+    }
+
+    #[test]
+    fn substring_search_ignores_case() {
+        let items = [item(Some("Hello World")), item(None), item(Some("bye"))];
+        assert_eq!(
+            search(&items, &[0, 1, 2], "WORLD", SearchMode::Substring),
+            Ok(vec![0])
+        );
+    }
+
+    #[test]
+    fn search_only_filters_the_visible_items() {
+        let items = [item(Some("one")), item(Some("one more"))];
+        assert_eq!(
+            search(&items, &[1], "one", SearchMode::Substring),
+            Ok(vec![1])
+        );
+    }
+
+    #[test]
+    fn fuzzy_search_puts_the_best_match_first() {
+        let items = [
+            item(Some("cargo build --release")),
+            item(Some("cat README.md")),
+            item(Some("git commit")),
+        ];
+        assert_eq!(
+            search(&items, &[0, 1, 2], "crgbld", SearchMode::Fuzzy),
+            Ok(vec![0])
+        );
+        assert_eq!(
+            search(&items, &[0, 1, 2], "cat", SearchMode::Fuzzy).unwrap()[0],
+            1
+        );
+    }
+
+    #[test]
+    fn regex_search_keeps_the_order() {
+        let items = [item(Some("v1.2")), item(Some("vim")), item(Some("v10.0"))];
+        assert_eq!(
+            search(&items, &[2, 1, 0], r"^v\d+\.", SearchMode::Regex),
+            Ok(vec![2, 0])
+        );
+    }
+
+    #[test]
+    fn invalid_regex_is_an_error() {
+        let items = [item(Some("(unclosed"))];
+        assert!(search(&items, &[0], "(unclosed", SearchMode::Regex).is_err());
+        // Only the regex mode parses the query.
+        assert_eq!(
+            search(&items, &[0], "(unclosed", SearchMode::Substring),
+            Ok(vec![0])
+        );
+    }
+
+    // Changes the time zone of the whole process, so all cases needing one are in this test.
+    #[test]
+    fn local_day_starts_follow_the_time_zone() {
+        unsafe extern "C" {
+            fn tzset();
+        }
+        // SAFETY: no other test reads the environment or the local time.
+        unsafe {
+            // Berlin, without depending on the installed time zone database.
+            std::env::set_var("TZ", "CET-1CEST,M3.5.0,M10.5.0/3");
+            tzset();
+        }
+
+        // 2026-03-30 14:00 CEST, the day after the switch to summer time, which had 23 hours.
+        let starts = local_day_starts(Duration::from_secs(1774872000));
+        assert_eq!(starts, Some((1774821600000, 1774738800000)));
+
+        // Exactly midnight belongs to the new day, a second before to the old one.
+        let starts = local_day_starts(Duration::from_secs(1774821600));
+        assert_eq!(starts, Some((1774821600000, 1774738800000)));
+        let starts = local_day_starts(Duration::from_secs(1774821599));
+        assert_eq!(starts.map(|(today, _)| today), Some(1774738800000));
+
+        // 2026-10-26 12:00 CET, the day after the switch back, which had 25 hours.
+        let starts = local_day_starts(Duration::from_secs(1793012400));
+        assert_eq!(starts, Some((1792969200000, 1792879200000)));
+
+        // 2026-03-01 12:00 CET, so yesterday is in the previous month.
+        let starts = local_day_starts(Duration::from_secs(1772362800));
+        assert_eq!(starts, Some((1772319600000, 1772233200000)));
+    }
+
+    #[test]
+    fn time_sections() {
+        let today = 2 * 86_400_000;
+        let yesterday = 86_400_000;
+        let now = Duration::from_millis(today + 12 * 3_600_000);
+        let section = |created_time| TimeSection::of(created_time, now, (today, yesterday));
+
+        assert_eq!(section(today + 12 * 3_600_000), TimeSection::JustNow);
+        assert_eq!(
+            section(today + 11 * 3_600_000 + 51 * 60_000),
+            TimeSection::JustNow
+        );
+        assert_eq!(section(today + 11 * 3_600_000), TimeSection::EarlierToday);
+        assert_eq!(section(today), TimeSection::EarlierToday);
+        assert_eq!(section(today - 1), TimeSection::Yesterday);
+        assert_eq!(section(yesterday), TimeSection::Yesterday);
+        assert_eq!(section(yesterday - 1), TimeSection::Older);
+    }
+
+    #[test]
+    fn transforms() {
+        let text = "  Hello\n\n   World  \n";
+        assert_eq!(Transform::Trim.apply(text), "Hello\n\n   World");
+        assert_eq!(Transform::Lowercase.apply("Grüße"), "grüße");
+        assert_eq!(Transform::Uppercase.apply("Grüße"), "GRÜSSE");
+        assert_eq!(Transform::CollapseNewlines.apply(text), "Hello World");
+        assert_eq!(Transform::CollapseNewlines.apply("a\r\nb"), "a b");
+    }
+
+    #[test]
+    fn transforms_have_distinct_keys_and_names() {
+        for (idx, transform) in Transform::ALL.iter().enumerate() {
+            for other in &Transform::ALL[idx + 1..] {
+                assert_ne!(transform.key(), other.key());
+                assert_ne!(transform.name(), other.name());
+            }
+        }
+    }
+
+    #[test]
+    fn byte_sizes() {
+        assert_eq!(format_byte_size(999), "999 B");
+        assert_eq!(format_byte_size(1000), "1.0 KB");
+        assert_eq!(format_byte_size(1_500_000), "1.5 MB");
+        assert_eq!(format_byte_size(2_000_000_000_000), "2000.0 GB");
+    }
+}
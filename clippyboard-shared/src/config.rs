@@ -0,0 +1,207 @@
+use std::path::PathBuf;
+
+use eyre::Context;
+use serde::Deserialize;
+
+/// The configuration file, shared by all binaries.
+/// Every key is optional, a missing file is the same as an empty one.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub daemon: DaemonConfig,
+    pub select: SelectConfig,
+    pub sync: SyncConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// Offers that advertise any of these MIME types are ignored entirely.
+    pub ignored_mime_types: Vec<String>,
+    /// Like `ignored_mime_types`, but `*` matches any number of characters.
+    pub ignored_mime_patterns: Vec<String>,
+    /// Don't store text entries that are empty or only contain whitespace.
+    pub skip_blank_text: bool,
+    /// Don't store text entries shorter than this many bytes, like accidental single characters.
+    pub min_text_bytes: usize,
+    /// Items older than this many seconds are removed from the history.
+    pub max_age_secs: Option<u64>,
+    /// Byte limit for `text/*` items, evicting only old text items when it is reached.
+    /// The limit for the whole history still applies.
+    pub max_text_bytes: Option<usize>,
+    /// Like `max_text_bytes`, but for `image/*` items.
+    pub max_image_bytes: Option<usize>,
+    /// A text entry that extends the previous text entry and was copied within this many
+    /// milliseconds of it replaces it, instead of being stored next to it.
+    /// This avoids storing every step of a growing selection.
+    pub merge_text_within_ms: Option<u64>,
+    /// Shell command run after every stored entry, with `CLIPPYBOARD_ID`, `CLIPPYBOARD_MIME`
+    /// and `CLIPPYBOARD_SIZE` set.
+    pub capture_hook: Option<String>,
+    /// Names of the seats to monitor. Empty means all seats.
+    pub seats: Vec<String>,
+    /// Reading a new clipboard entry is abandoned when the source sends no data for this long.
+    pub capture_timeout_secs: u64,
+    /// When a new item counts as a duplicate of the previous one and isn't stored.
+    pub dedup: DedupMode,
+    /// Never evict, expire, clear or range-delete items that have a tag. They don't count towards
+    /// the byte limits.
+    pub keep_tagged: bool,
+    /// Store images at all. Without, offers with text store the text and image-only ones nothing.
+    /// Overridden by the `CLIPPYBOARD_STORE_IMAGES` environment variable, `0` or `1`.
+    pub store_images: bool,
+    /// Images wider or taller than this many pixels are scaled down to fit and stored as PNG.
+    /// Unset keeps images exactly as they were copied.
+    pub max_image_dimension: Option<u32>,
+    /// Create the thumbnail of every image when it's copied and store it with the entry.
+    /// Otherwise, thumbnails are created when the history list first shows the image.
+    pub image_thumbnails: bool,
+    /// Serve the history read-only over HTTP on this port on localhost.
+    /// Only available when built with the `http` feature.
+    pub http_port: Option<u16>,
+    /// When more than this many entries are copied within `capture_burst_window_ms` of each
+    /// other, only the last one of them is stored, once nothing was copied for that long.
+    /// For apps that keep rewriting the clipboard.
+    pub capture_burst_limit: Option<usize>,
+    pub capture_burst_window_ms: u64,
+    /// For how many seconds a clear can be undone with `clippyboard-clear --undo`.
+    /// Storing a new entry also ends it. 0 disables undoing.
+    pub undo_clear_secs: u64,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        Self {
+            ignored_mime_types: Vec::new(),
+            ignored_mime_patterns: Vec::new(),
+            skip_blank_text: false,
+            min_text_bytes: 0,
+            max_age_secs: None,
+            max_text_bytes: None,
+            max_image_bytes: None,
+            merge_text_within_ms: None,
+            capture_hook: None,
+            seats: Vec::new(),
+            capture_timeout_secs: 30,
+            dedup: DedupMode::default(),
+            keep_tagged: false,
+            store_images: true,
+            max_image_dimension: None,
+            image_thumbnails: false,
+            http_port: None,
+            capture_burst_limit: None,
+            capture_burst_window_ms: 1000,
+            undo_clear_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DedupMode {
+    /// Same MIME type and data.
+    #[default]
+    Exact,
+    /// Same data, no matter the MIME type. The previous item takes the new MIME type.
+    IgnoreMime,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SelectConfig {
+    /// Width and height of the window. When unset, the size from the last run is used.
+    pub window_size: Option<[f32; 2]>,
+    /// Open the window in the center of the screen, if the compositor lets us.
+    pub centered: bool,
+    pub theme: Theme,
+    /// Color of the selection outline as `#rrggbb`.
+    pub accent_color: Option<String>,
+    /// Text in the history list is cut off after this many characters.
+    pub list_preview_chars: usize,
+    /// Text in the detail panel is cut off after this many characters,
+    /// since rendering huge texts makes the GUI slow.
+    pub detail_max_chars: usize,
+    /// Show the oldest entries at the top.
+    pub oldest_first: bool,
+    pub search_mode: SearchMode,
+    /// Factor for the size of everything in the window, on top of the scale of the screen.
+    /// Overridden by the `CLIPPYBOARD_UI_SCALE` environment variable.
+    pub ui_scale: f32,
+    /// Close the window without copying when it loses focus, like a popup.
+    pub close_on_focus_loss: bool,
+    /// Show the history as single lines with less padding, to fit more entries on the screen.
+    pub compact: bool,
+}
+
+impl Default for SelectConfig {
+    fn default() -> Self {
+        Self {
+            window_size: None,
+            centered: false,
+            theme: Theme::default(),
+            accent_color: None,
+            list_preview_chars: 1000,
+            detail_max_chars: 100_000,
+            oldest_first: false,
+            search_mode: SearchMode::default(),
+            ui_scale: 1.0,
+            close_on_focus_loss: false,
+            compact: false,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchMode {
+    /// Entries containing the query, ignoring case.
+    #[default]
+    Substring,
+    /// Entries matching the query fuzzily, best matches first.
+    Fuzzy,
+    /// Entries matching the query as a regular expression.
+    Regex,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyncConfig {
+    /// Socket of the daemon on the other machine, usually forwarded with `ssh -L`.
+    pub remote_socket: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    /// Follow the light/dark preference of the desktop.
+    #[default]
+    System,
+    Light,
+    Dark,
+}
+
+impl Config {
+    pub fn load() -> eyre::Result<Self> {
+        let Some(path) = config_path() else {
+            return Ok(Self::default());
+        };
+
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => {
+                return Err(err).wrap_err_with(|| format!("reading {}", path.display()));
+            }
+        };
+
+        toml::from_str(&content).wrap_err_with(|| format!("parsing {}", path.display()))
+    }
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("CLIPPYBOARD_CONFIG") {
+        return Some(path.into());
+    }
+
+    dirs::config_dir().map(|dir| dir.join("clippyboard").join("config.toml"))
+}
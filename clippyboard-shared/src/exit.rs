@@ -0,0 +1,54 @@
+//! Exit codes for the failures that scripts and supervisors may want to react to.
+//! Every other error exits with 1, and the daemon with 130 when it's interrupted.
+
+use std::fmt;
+use std::process::ExitCode;
+
+/// Attached to an error with `wrap_err` to give it its own exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// No Wayland compositor to connect to.
+    WaylandUnavailable,
+    /// The compositor supports none of the clipboard protocols the daemon can use.
+    ProtocolUnsupported,
+    /// Another daemon is listening on the socket.
+    SocketInUse,
+    /// Nothing is listening on the socket.
+    DaemonNotRunning,
+}
+
+impl Failure {
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Failure::WaylandUnavailable => 3,
+            Failure::ProtocolUnsupported => 4,
+            Failure::SocketInUse => 5,
+            Failure::DaemonNotRunning => 6,
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Failure::WaylandUnavailable => "could not connect to the Wayland compositor",
+            Failure::ProtocolUnsupported => "the compositor does not support clipboard managers",
+            Failure::SocketInUse => "another daemon is already running",
+            Failure::DaemonNotRunning => "the daemon is not running",
+        })
+    }
+}
+
+/// Prints the error like returning it from `main` does, with the exit code of its [`Failure`].
+pub fn report(result: eyre::Result<()>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            let code = err
+                .downcast_ref::<Failure>()
+                .map_or(1, |failure| failure.exit_code());
+            ExitCode::from(code)
+        }
+    }
+}
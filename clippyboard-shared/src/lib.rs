@@ -1,18 +1,125 @@
-use std::{path::PathBuf, sync::Arc};
+//! The socket protocol between the daemon and its clients, and the config they share.
+//!
+//! Clients connect to the socket at [`socket_path`] and send [`PROTOCOL_VERSION`] as one byte.
+//! The daemon answers with its own version and closes the connection if they differ.
+//! Then the client sends any number of messages, each one of the `MESSAGE_*` bytes followed by
+//! its argument. Numbers are little-endian, and items are encoded as CBOR, see [`HistoryItem`].
+//! An unknown message closes the connection.
 
-use eyre::OptionExt;
+pub mod config;
+pub mod exit;
+
+use std::{
+    io::{self, Read, Write},
+    os::unix::{net::UnixStream, process::CommandExt},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use eyre::Context;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
+/// An entry of the history, as sent by [`MESSAGE_READ`] and [`MESSAGE_SUBSCRIBE`].
+///
+/// Encoded as a CBOR map with the field names given by the `rename` attributes, which stay the
+/// same even if the Rust fields are renamed. Fields are only added with a default, so clients
+/// should ignore fields they don't know. Removing or changing a field bumps [`PROTOCOL_VERSION`].
 #[derive(Clone, serde::Deserialize, serde::Serialize)]
 pub struct HistoryItem {
+    /// Unique for the lifetime of the daemon, higher for newer items.
+    #[serde(rename = "id")]
     pub id: u64,
+    /// The MIME type the data is provided as.
+    #[serde(rename = "mime")]
     pub mime: String,
+    /// The content, a CBOR byte string.
     #[serde(
+        rename = "data",
         deserialize_with = "deserialize_data",
         serialize_with = "serialize_data"
     )]
     pub data: Arc<[u8]>,
+    /// When it was copied, in milliseconds since the UNIX epoch.
+    #[serde(rename = "created_time")]
+    pub created_time: u64,
+    /// XXH3 64-bit hash of `data`, cheap to compare before comparing the data itself.
+    #[serde(rename = "hash", default)]
+    pub hash: u64,
+    /// How often this item has been copied from the history.
+    #[serde(rename = "copy_count", default)]
+    pub copy_count: u64,
+    /// Name of the seat it was copied on, if it came from a named seat.
+    #[serde(rename = "seat", default)]
+    pub seat: Option<String>,
+    /// The mime type the content was offered as, if it was normalized to a different `mime`.
+    #[serde(rename = "original_mime", default)]
+    pub original_mime: Option<String>,
+    /// Labels attached by the user, see [`MESSAGE_TAG`].
+    #[serde(rename = "tags", default)]
+    pub tags: Vec<String>,
+    /// A small PNG of image items, when the daemon is configured to create them.
+    #[serde(rename = "thumbnail", default)]
+    pub thumbnail: Option<Vec<u8>>,
+    /// The application it was copied from, as far as it can be told from the offered MIME types.
+    #[serde(rename = "source", default)]
+    pub source: Option<String>,
+}
+
+/// A [`HistoryItem`] without its data, see [`MESSAGE_READ_META`].
+/// Encoded like [`HistoryItem`], the fields mean the same as there.
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+pub struct HistoryItemMeta {
+    #[serde(rename = "id")]
+    pub id: u64,
+    #[serde(rename = "mime")]
+    pub mime: String,
+    /// Length of the data in bytes.
+    #[serde(rename = "size")]
+    pub size: u64,
+    #[serde(rename = "created_time")]
     pub created_time: u64,
+    #[serde(rename = "hash")]
+    pub hash: u64,
+    #[serde(rename = "copy_count")]
+    pub copy_count: u64,
+    #[serde(rename = "seat")]
+    pub seat: Option<String>,
+    /// The start of the data for `text/*` items, `None` for everything else.
+    #[serde(rename = "preview")]
+    pub preview: Option<String>,
+    #[serde(rename = "tags")]
+    pub tags: Vec<String>,
+    #[serde(rename = "thumbnail")]
+    pub thumbnail: Option<Vec<u8>>,
+    #[serde(rename = "source")]
+    pub source: Option<String>,
+}
+
+impl HistoryItemMeta {
+    /// Keeps at most `preview_chars` characters of text as the preview.
+    pub fn new(item: &HistoryItem, preview_chars: usize) -> Self {
+        let preview = item.mime.starts_with("text/").then(|| {
+            String::from_utf8_lossy(&item.data)
+                .chars()
+                .take(preview_chars)
+                .collect()
+        });
+        Self {
+            id: item.id,
+            mime: item.mime.clone(),
+            size: item.data.len() as u64,
+            created_time: item.created_time,
+            hash: item.hash,
+            copy_count: item.copy_count,
+            seat: item.seat.clone(),
+            preview,
+            tags: item.tags.clone(),
+            thumbnail: item.thumbnail.clone(),
+            source: item.source.clone(),
+        }
+    }
 }
 
 fn deserialize_data<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Arc<[u8]>, D::Error> {
@@ -24,17 +131,243 @@ fn serialize_data<S: Serializer>(data: &Arc<[u8]>, serializer: S) -> Result<S::O
     data.serialize(serializer)
 }
 
+/// Sent by the client as the first byte of every connection. The daemon answers with its own
+/// version and closes the connection if they differ.
+/// Bump this whenever [`HistoryItem`] or the messages change.
+pub const PROTOCOL_VERSION: u8 = 17;
+
+/// Answered with every [`HistoryItem`] as a CBOR array, oldest first.
 pub const MESSAGE_READ: u8 = 1;
 /// Argument: One u64-bit LE value, the ID
 pub const MESSAGE_COPY: u8 = 2;
+/// Argument: One byte, one of the `CLEAR_*` categories, then one byte that is 1 to also clear
+/// entries that `keep_tagged` keeps and 0 otherwise
 pub const MESSAGE_CLEAR: u8 = 3;
+/// Sets the clipboard to arbitrary content without storing it in the history.
+/// Argument: The MIME type and then the data, each framed with [`write_framed`]
+pub const MESSAGE_SET_SELECTION: u8 = 4;
+/// Stores arbitrary content in the history as if it had been copied.
+/// Argument: The MIME type and then the data, each framed with [`write_framed`]
+pub const MESSAGE_STORE_RAW: u8 = 5;
+/// Writes an image entry to a temporary file and puts its `file://` URI on the clipboard as `text/uri-list`.
+/// Argument: One u64-bit LE value, the ID
+pub const MESSAGE_COPY_AS_FILE: u8 = 6;
+/// Deletes all items older or newer than an item, not including the item itself.
+/// Argument: One u64-bit LE value, the ID, and then one byte, one of the `DELETE_*` directions.
+/// Answered with the number of deleted items as a u64 LE value, followed by their IDs as u64 LE
+/// values. Items that the daemon keeps, see `keep_tagged`, are not deleted.
+pub const MESSAGE_DELETE_RANGE: u8 = 7;
+/// Like [`MESSAGE_READ`], but answers with a [`HistoryItemMeta`] for every item, without the data.
+/// Images always have a thumbnail here, the daemon creates missing ones.
+/// Argument: One u64-bit LE value, the maximum number of characters of the text previews
+pub const MESSAGE_READ_META: u8 = 8;
+/// Reads the data of a single item. Answered with one byte, 1 if the item exists and 0 otherwise,
+/// and then the data framed with [`write_framed`] if it exists.
+/// Argument: One u64-bit LE value, the ID
+pub const MESSAGE_GET: u8 = 9;
+/// Answered with the same byte, to check whether the daemon is running.
+pub const MESSAGE_PING: u8 = 10;
+/// Adds a tag to an item, if it doesn't have it yet.
+/// Argument: One u64-bit LE value, the ID, and then the tag framed with [`write_framed`]
+pub const MESSAGE_TAG: u8 = 11;
+/// Removes a tag from an item. Same argument as [`MESSAGE_TAG`].
+pub const MESSAGE_UNTAG: u8 = 12;
+/// Like [`MESSAGE_COPY`], but puts the item in the primary selection, for middle-click pasting.
+/// Argument: One u64-bit LE value, the ID
+pub const MESSAGE_COPY_PRIMARY: u8 = 13;
+/// Turns the connection into a stream of new items. Every newly stored [`HistoryItem`] is sent
+/// as CBOR framed with [`write_framed`]. No further messages are read from the connection.
+pub const MESSAGE_SUBSCRIBE: u8 = 14;
+/// Brings back the history from before the last [`MESSAGE_CLEAR`], if it was cleared within
+/// the configured time and nothing was stored since. Answered with one byte, 1 if the clear
+/// was undone and 0 otherwise.
+pub const MESSAGE_UNDO_CLEAR: u8 = 16;
+
+pub const DELETE_OLDER: u8 = 0;
+pub const DELETE_NEWER: u8 = 1;
+
+pub const CLEAR_ALL: u8 = 0;
+/// Only clears entries with a `text/*` MIME type.
+pub const CLEAR_TEXT: u8 = 1;
+/// Only clears entries with an `image/*` MIME type.
+pub const CLEAR_IMAGES: u8 = 2;
+
+/// Writes `data` prefixed by its length as a u64 LE value.
+pub fn write_framed(mut writer: impl Write, data: &[u8]) -> io::Result<()> {
+    writer.write_all(&(data.len() as u64).to_le_bytes())?;
+    writer.write_all(data)
+}
+
+/// Reads data written by [`write_framed`], refusing to read more than `max_len` bytes.
+pub fn read_framed(mut reader: impl Read, max_len: u64) -> io::Result<Vec<u8>> {
+    let mut len = [0; 8];
+    reader.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len);
+    if len > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {len} bytes exceeds the limit of {max_len} bytes"),
+        ));
+    }
+
+    let mut data = vec![0; len as usize];
+    reader.read_exact(&mut data)?;
+    Ok(data)
+}
 
-pub fn socket_path() -> eyre::Result<PathBuf> {
+/// Where the daemon listens, see [`socket_path`].
+pub struct SocketPath {
+    pub path: PathBuf,
+    /// `XDG_RUNTIME_DIR` is not set, so `path` is in `/tmp` instead. Left to the caller to warn
+    /// about, since only the daemon logs through `tracing`.
+    pub is_fallback: bool,
+}
+
+pub fn socket_path() -> SocketPath {
     if let Some(path) = std::env::var_os("CLIPPYBOARD_SOCKET") {
-        return Ok(path.into());
+        return SocketPath {
+            path: path.into(),
+            is_fallback: false,
+        };
+    }
+
+    match dirs::runtime_dir() {
+        Some(dir) => SocketPath {
+            path: dir.join("clippyboard.sock"),
+            is_fallback: false,
+        },
+        None => SocketPath {
+            path: PathBuf::from(format!(
+                "/tmp/clippyboard-{}.sock",
+                rustix::process::getuid().as_raw()
+            )),
+            is_fallback: true,
+        },
+    }
+}
+
+/// The `--version` output of the calling binary, its name and version and the protocol version.
+#[macro_export]
+macro_rules! version {
+    () => {
+        format!(
+            "{} {} (protocol version {})",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+            $crate::PROTOCOL_VERSION
+        )
+    };
+}
+
+/// How long [`connect_or_start_daemon`] waits for a started daemon to listen on the socket.
+const AUTOSTART_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connects to the daemon and performs the version handshake.
+pub fn connect(socket_path: &Path) -> eyre::Result<UnixStream> {
+    let socket = match UnixStream::connect(socket_path) {
+        Ok(socket) => socket,
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+            ) =>
+        {
+            return Err(err)
+                .wrap_err_with(|| format!("connecting to socket at {}", socket_path.display()))
+                .wrap_err(exit::Failure::DaemonNotRunning);
+        }
+        Err(err) => {
+            return Err(err)
+                .wrap_err_with(|| format!("connecting to socket at {}", socket_path.display()));
+        }
+    };
+    handshake(socket)
+}
+
+/// Like [`connect`], but starts `clippyboard-daemon` in the background first if nothing is
+/// listening on the socket, for the `--autostart` flag of the clients.
+pub fn connect_or_start_daemon(socket_path: &Path) -> eyre::Result<UnixStream> {
+    match UnixStream::connect(socket_path) {
+        Ok(socket) => return handshake(socket),
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+            ) => {}
+        Err(_) => return connect(socket_path),
+    }
+
+    // Prefer the daemon installed next to us over the one in PATH.
+    let daemon = std::env::current_exe()
+        .map(|exe| exe.with_file_name("clippyboard-daemon"))
+        .ok()
+        .filter(|daemon| daemon.exists())
+        .unwrap_or_else(|| PathBuf::from("clippyboard-daemon"));
+    eprintln!(
+        "INFO: the daemon is not running, starting {}",
+        daemon.display()
+    );
+    Command::new(&daemon)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        // so that it doesn't get the signals sent to our terminal
+        .process_group(0)
+        .spawn()
+        .wrap_err_with(|| format!("starting {}", daemon.display()))?;
+
+    let start = Instant::now();
+    loop {
+        match UnixStream::connect(socket_path) {
+            Ok(socket) => return handshake(socket),
+            Err(_) if start.elapsed() < AUTOSTART_TIMEOUT => {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            Err(err) => {
+                return Err(err).wrap_err_with(|| {
+                    format!(
+                        "the started daemon did not listen on {} within {AUTOSTART_TIMEOUT:?}",
+                        socket_path.display()
+                    )
+                });
+            }
+        }
+    }
+}
+
+/// Checks that the daemon is running and answers, without reading the history.
+pub fn ping(socket_path: &Path) -> eyre::Result<()> {
+    let mut socket = connect(socket_path)?;
+    socket
+        .write_all(&[MESSAGE_PING])
+        .wrap_err("writing ping message to socket")?;
+
+    let mut response = [0; 1];
+    socket
+        .read_exact(&mut response)
+        .wrap_err("reading ping response")?;
+    if response[0] != MESSAGE_PING {
+        eyre::bail!("unexpected ping response {}", response[0]);
+    }
+
+    Ok(())
+}
+
+fn handshake(mut socket: UnixStream) -> eyre::Result<UnixStream> {
+    socket
+        .write_all(&[PROTOCOL_VERSION])
+        .wrap_err("writing protocol version")?;
+    let mut daemon_version = [0; 1];
+    socket
+        .read_exact(&mut daemon_version)
+        .wrap_err("reading protocol version of the daemon")?;
+    if daemon_version[0] != PROTOCOL_VERSION {
+        eyre::bail!(
+            "the daemon speaks protocol version {}, but this client speaks version {PROTOCOL_VERSION}. \
+            make sure the daemon and clients are from the same version of clippyboard",
+            daemon_version[0]
+        );
     }
 
-    Ok(dirs::runtime_dir()
-        .ok_or_eyre("missing XDG_RUNTIME_DIR")?
-        .join("clippyboard.sock"))
+    Ok(socket)
 }
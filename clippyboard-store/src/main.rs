@@ -0,0 +1,110 @@
+//! Stores stdin (or the arguments) in the history, like `wl-copy` does for the clipboard.
+
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+
+use eyre::{Context, ContextCompat, bail};
+
+const USAGE: &str = "\
+Usage: clippyboard-store [OPTIONS] [TEXT]...
+
+Stores the text arguments, or stdin if there are none, in the clipboard history.
+
+Options:
+  -t, --type <MIME>  MIME type of the data, detected for text, PNG and JPEG by default
+      --wl-copy      Also put the data on the clipboard
+  -V, --version      Print the version
+  -h, --help         Print this help
+";
+
+fn main() -> std::process::ExitCode {
+    clippyboard_shared::exit::report(run())
+}
+
+fn run() -> eyre::Result<()> {
+    let mut mime = None;
+    // Also put the data on the clipboard, so this can replace `wl-copy` in scripts.
+    let mut wl_copy = false;
+    let mut text_args = Vec::new();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--type" | "-t" => mime = Some(args.next().wrap_err("--type requires a MIME type")?),
+            "--wl-copy" => wl_copy = true,
+            "--help" | "-h" => {
+                print!("{USAGE}");
+                return Ok(());
+            }
+            "--version" | "-V" => {
+                println!("{}", clippyboard_shared::version!());
+                return Ok(());
+            }
+            _ if arg.starts_with('-') => bail!("unknown argument {arg}, see --help"),
+            _ => text_args.push(arg),
+        }
+    }
+
+    let data = if text_args.is_empty() {
+        let mut data = Vec::new();
+        std::io::stdin()
+            .read_to_end(&mut data)
+            .wrap_err("reading stdin")?;
+        data
+    } else {
+        text_args.join(" ").into_bytes()
+    };
+
+    if data.is_empty() {
+        return Ok(());
+    }
+
+    let mime = match mime {
+        Some(mime) => mime,
+        None => detect_mime(&data)
+            .wrap_err("failed to detect the MIME type of the data, pass it with --type")?
+            .to_owned(),
+    };
+
+    let socket_path = clippyboard_shared::socket_path().path;
+    let mut socket = clippyboard_shared::connect(&socket_path)?;
+    send(
+        &mut socket,
+        clippyboard_shared::MESSAGE_STORE_RAW,
+        &mime,
+        &data,
+    )?;
+    if wl_copy {
+        // Not stored again, it is already in the history.
+        send(
+            &mut socket,
+            clippyboard_shared::MESSAGE_SET_SELECTION,
+            &mime,
+            &data,
+        )?;
+    }
+
+    Ok(())
+}
+
+fn detect_mime(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if data.starts_with(b"\xff\xd8\xff") {
+        Some("image/jpg")
+    } else if str::from_utf8(data).is_ok() {
+        Some("text/plain")
+    } else {
+        None
+    }
+}
+
+fn send(socket: &mut UnixStream, message: u8, mime: &str, data: &[u8]) -> eyre::Result<()> {
+    socket
+        .write_all(&[message])
+        .wrap_err("writing request type")?;
+    clippyboard_shared::write_framed(&mut *socket, mime.as_bytes())
+        .wrap_err("writing mime type")?;
+    clippyboard_shared::write_framed(&mut *socket, data).wrap_err("writing data")?;
+    Ok(())
+}
@@ -0,0 +1,175 @@
+//! Mirrors new entries between the local daemon and a remote one, for example one whose socket
+//! is forwarded with `ssh -L`. A side announces the entries we send it as new too, so the next
+//! entry from a side with the hash of the one last sent to it isn't sent back.
+
+use std::io::{BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+use clippyboard_shared::config::Config;
+use clippyboard_shared::{HistoryItem, HistoryItemMeta};
+use eyre::{Context, ContextCompat, bail};
+
+struct Side {
+    name: &'static str,
+    socket: UnixStream,
+}
+
+impl Side {
+    fn read_items(&mut self) -> eyre::Result<Vec<HistoryItemMeta>> {
+        self.socket
+            .write_all(&[clippyboard_shared::MESSAGE_READ_META])
+            .wrap_err("writing request type")?;
+        self.socket
+            .write_all(&0_u64.to_le_bytes())
+            .wrap_err("writing preview length")?;
+        ciborium::from_reader(BufReader::new(&self.socket))
+            .wrap_err_with(|| format!("reading items of the {} daemon", self.name))
+    }
+
+    /// Stores the entry, and also puts it on the clipboard if `set_selection` is set.
+    fn store(&mut self, item: &HistoryItem, set_selection: bool) -> eyre::Result<()> {
+        let mut messages = vec![clippyboard_shared::MESSAGE_STORE_RAW];
+        if set_selection {
+            // Not stored again, it is already in the history.
+            messages.push(clippyboard_shared::MESSAGE_SET_SELECTION);
+        }
+        for message in messages {
+            self.socket
+                .write_all(&[message])
+                .wrap_err("writing request type")?;
+            clippyboard_shared::write_framed(&mut self.socket, item.mime.as_bytes())
+                .wrap_err("writing mime type")?;
+            clippyboard_shared::write_framed(&mut self.socket, &item.data)
+                .wrap_err("writing data")?;
+        }
+        Ok(())
+    }
+}
+
+/// Sends the index of the side with every new item stored there.
+fn subscribe(
+    side: usize,
+    socket_path: &Path,
+    send: mpsc::Sender<eyre::Result<(usize, HistoryItem)>>,
+) -> eyre::Result<()> {
+    let mut socket = clippyboard_shared::connect(socket_path)?;
+    socket
+        .write_all(&[clippyboard_shared::MESSAGE_SUBSCRIBE])
+        .wrap_err("writing request type")?;
+
+    std::thread::spawn(move || {
+        loop {
+            let item = clippyboard_shared::read_framed(&socket, u64::MAX)
+                .wrap_err("reading new item")
+                .and_then(|frame| {
+                    ciborium::from_reader(frame.as_slice()).wrap_err("decoding new item")
+                });
+            let failed = item.is_err();
+            if send.send(item.map(|item| (side, item))).is_err() || failed {
+                break;
+            }
+        }
+    });
+    Ok(())
+}
+
+const USAGE: &str = "\
+Usage: clippyboard-sync [OPTIONS]
+
+Mirrors new clipboard entries between the local daemon and a remote one.
+
+Options:
+      --remote <SOCKET>  Socket of the remote daemon, overrides sync.remote_socket
+  -V, --version          Print the version
+  -h, --help             Print this help
+";
+
+fn main() -> std::process::ExitCode {
+    clippyboard_shared::exit::report(run())
+}
+
+fn run() -> eyre::Result<()> {
+    let config = Config::load().wrap_err("loading config")?.sync;
+
+    let mut remote_socket = config.remote_socket;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--remote" => {
+                remote_socket = Some(
+                    args.next()
+                        .wrap_err("--remote requires a socket path")?
+                        .into(),
+                )
+            }
+            "--help" | "-h" => {
+                print!("{USAGE}");
+                return Ok(());
+            }
+            "--version" | "-V" => {
+                println!("{}", clippyboard_shared::version!());
+                return Ok(());
+            }
+            _ => bail!("unknown argument {arg}, see --help"),
+        }
+    }
+    let remote_socket = remote_socket
+        .wrap_err("no remote socket configured, set sync.remote_socket or pass --remote")?;
+
+    let socket_paths: [(&'static str, PathBuf); 2] = [
+        ("local", clippyboard_shared::socket_path().path),
+        ("remote", remote_socket),
+    ];
+    let (send, new_items) = mpsc::channel();
+    let mut sides = Vec::new();
+    for (idx, (name, socket_path)) in socket_paths.iter().enumerate() {
+        let socket = clippyboard_shared::connect(socket_path)
+            .wrap_err_with(|| format!("connecting to the {name} daemon"))?;
+        sides.push(Side { name, socket });
+        subscribe(idx, socket_path, send.clone())
+            .wrap_err_with(|| format!("subscribing to the {name} daemon"))?;
+    }
+
+    // Hash of the entry last sent to each side, until the side announces it.
+    let mut sent = [None; 2];
+    for new_item in new_items {
+        let (from, item) = new_item?;
+        let to = 1 - from;
+
+        if sent[from] == Some(item.hash) {
+            sent[from] = None;
+            continue;
+        }
+
+        let target_items = sides[to].read_items()?;
+        // The daemon doesn't store or announce a copy of its newest entry, which happens when
+        // the same thing was copied on both sides.
+        if target_items
+            .iter()
+            .max_by_key(|existing| existing.created_time)
+            .is_some_and(|newest| newest.hash == item.hash && newest.mime == item.mime)
+        {
+            continue;
+        }
+        sent[to] = Some(item.hash);
+
+        // When entries were copied on both sides at once, the most recent one stays
+        // on the clipboard.
+        let is_most_recent = target_items
+            .iter()
+            .all(|existing| existing.created_time <= item.created_time);
+
+        sides[to].store(&item, is_most_recent)?;
+        println!(
+            "INFO: sent {} entry {} ({} bytes) to the {} daemon",
+            sides[from].name,
+            item.id,
+            item.data.len(),
+            sides[to].name
+        );
+    }
+
+    Ok(())
+}